@@ -0,0 +1,110 @@
+//! Compares the byte-oriented parser with the original `Vec<char>` scanner and, with the
+//! `regex` feature, the regex backend on multi-megabyte inputs.
+//!
+//! Run with `cargo bench --features regex`.
+
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use expand_env_vars::{Options, Syntax};
+
+const SIZE: usize = 4 << 20;
+const RUNS: usize = 10;
+
+/// The `Vec<char>` scanner the crate used before parsing into an AST, kept as a baseline.
+mod legacy {
+    use expand_env_vars::VarSource;
+
+    pub fn expand(input: &str, source: &dyn VarSource) -> String {
+        let mut result = String::with_capacity(input.len());
+        let chars: Vec<char> = input.chars().collect();
+        let mut i = 0;
+
+        while i < chars.len() {
+            if chars[i] == '$' {
+                if i + 1 < chars.len() && chars[i + 1] == '{' {
+                    let mut j = i + 2;
+                    while j < chars.len() && chars[j] != '}' {
+                        j += 1;
+                    }
+
+                    if j < chars.len() {
+                        let var_name: String = chars[i + 2..j].iter().collect();
+                        result.push_str(&source.var(&var_name).unwrap_or_default());
+                        i = j + 1;
+                    } else {
+                        result.push('$');
+                        i += 1;
+                    }
+                } else {
+                    let mut j = i + 1;
+                    while j < chars.len() && (chars[j].is_ascii_alphanumeric() || chars[j] == '_') {
+                        j += 1;
+                    }
+                    let var_name: String = chars[i + 1..j].iter().collect();
+                    result.push_str(&source.var(&var_name).unwrap_or_default());
+                    i = j;
+                }
+            } else {
+                result.push(chars[i]);
+                i += 1;
+            }
+        }
+
+        result
+    }
+}
+
+/// Repeats `unit` until the result is at least [`SIZE`] bytes.
+fn input(unit: &str) -> String {
+    unit.repeat(SIZE.div_ceil(unit.len()))
+}
+
+/// Runs `f` [`RUNS`] times and prints the median time and throughput.
+fn bench(group: &str, name: &str, len: usize, mut f: impl FnMut() -> String) {
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(f());
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    let median = times[RUNS / 2];
+    let throughput = len as f64 / median.as_secs_f64() / (1 << 20) as f64;
+    println!("{group:<10} {name:<16} {median:>12.2?} {throughput:>10.1} MiB/s");
+}
+
+fn main() {
+    let vars = HashMap::from([("HOME", "/home/alice"), ("USER", "alice")]);
+    let options = Options::new().syntax(Syntax::Unix);
+    let inputs = [
+        (
+            "literal",
+            input("Lorem ipsum dolor sit amet, consectetur adipiscing elit. "),
+        ),
+        (
+            "sparse",
+            input(&format!(
+                "{}$HOME/.config\n",
+                "plain text, no placeholders; ".repeat(140)
+            )),
+        ),
+        ("dense", input("path=${HOME}/bin:$USER ünïcödé\n")),
+    ];
+
+    for (group, input) in &inputs {
+        let len = input.len();
+        bench(group, "legacy", len, || legacy::expand(input, &vars));
+        bench(group, "parse+render", len, || {
+            options.expand_with(input, &vars).unwrap()
+        });
+        let template = options.parse(input).unwrap();
+        bench(group, "render", len, || template.render(&vars).unwrap());
+        #[cfg(all(feature = "regex", unix))]
+        bench(group, "regex", len, || {
+            expand_env_vars::regex::expand_with(input, &vars).unwrap()
+        });
+    }
+}
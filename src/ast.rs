@@ -0,0 +1,1042 @@
+//! Parse tree for expansion templates.
+//!
+//! [`parse`] turns an input into a list of [`Node`]s without evaluating anything, so tools can
+//! inspect or rewrite templates. Every node remembers its [`Span`] in the parsed input and
+//! enough of its original spelling to be written back out: formatting a list of nodes with
+//! [`to_source`] reproduces the input exactly. Nodes borrow their text from the input; use
+//! [`Node::into_owned`] to keep them longer.
+//!
+//! ```
+//! use expand_env_vars::{Options, Syntax};
+//! use expand_env_vars::ast::{self, Node};
+//!
+//! let options = Options::new().syntax(Syntax::Unix);
+//! let mut nodes = ast::parse("http://${HOST:-localhost}:$PORT/", &options).unwrap();
+//!
+//! ast::walk_mut(&mut nodes, &mut |node| {
+//!     if let Node::Var(var) = node {
+//!         var.name = format!("APP_{}", var.name).into();
+//!     }
+//! });
+//! assert_eq!(ast::to_source(&nodes), "http://${APP_HOST:-localhost}:$APP_PORT/");
+//! ```
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt;
+
+use memchr::{memchr, memchr2, memchr3, memmem};
+
+use crate::{EnvExpansionError, Escape, Location, Options, ParseErrorKind, Span, Syntax};
+
+/// A piece of a parsed template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node<'a> {
+    /// Literal text, copied to the output unchanged.
+    Text { text: Cow<'a, str>, span: Span },
+    /// An escape sequence such as `$$` or `\$`, which produces its last character.
+    Escape { raw: Cow<'a, str>, span: Span },
+    /// A variable reference.
+    Var(Var<'a>),
+    /// A tilde prefix such as `~`, `~+`, `~-` or `~user` at the start of a word, recognized
+    /// when [`Options::tilde`] is enabled. `user` is the text after the `~`.
+    Tilde { user: Cow<'a, str>, span: Span },
+}
+
+/// A variable reference such as `$VAR`, `${VAR:-default}` or `%VAR%`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Var<'a> {
+    /// Name of the variable.
+    pub name: Cow<'a, str>,
+    /// How the reference was spelled.
+    pub form: Form<'a>,
+    /// The operator of a braced `${VAR<op>word}` reference.
+    pub op: Option<Op<'a>>,
+    /// Location of the whole reference.
+    pub span: Span,
+}
+
+/// How a variable reference is spelled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Form<'a> {
+    /// `$VAR`
+    Simple,
+    /// `${VAR}`, possibly with an operator.
+    Braced,
+    /// `%VAR%`
+    Percent,
+    /// A name enclosed in the delimiters of [`Syntax::Custom`].
+    Custom {
+        open: Cow<'a, str>,
+        close: Cow<'a, str>,
+    },
+    /// `$(VAR)`, as in [`Syntax::Kubernetes`].
+    Paren,
+}
+
+/// An operator in a braced `${VAR<op>word}` expression, with its parsed `word`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op<'a> {
+    /// `${VAR:-word}` / `${VAR-word}`: use `word` if `VAR` is unset (or empty, with the colon).
+    Default { colon: bool, word: Vec<Node<'a>> },
+    /// `${VAR:?message}` / `${VAR?message}`: fail with `message` if `VAR` is unset (or empty,
+    /// with the colon).
+    Error { colon: bool, word: Vec<Node<'a>> },
+    /// `${VAR:+word}` / `${VAR+word}`: use `word` if `VAR` is set (and non-empty, with the
+    /// colon), otherwise nothing.
+    Alternate { colon: bool, word: Vec<Node<'a>> },
+    /// `${VAR:=word}` / `${VAR=word}`: like [`Op::Default`], but also assigns `word` to `VAR`
+    /// for the rest of the expansion.
+    Assign { colon: bool, word: Vec<Node<'a>> },
+    /// cmd.exe's `%VAR:~start%` / `%VAR:~start,length%`: `length` characters of the value
+    /// from `start`, or the rest of it without `length`. A negative `start` counts from the
+    /// end, and a negative `length` leaves that many characters off the end. Values that are
+    /// not valid Unicode are converted lossily first. `spec` is the text after `:~` as written,
+    /// so `%VAR:~+007%` is written back unchanged.
+    Substring {
+        start: i64,
+        length: Option<i64>,
+        spec: Cow<'a, str>,
+    },
+}
+
+/// The kind of an [`Op`], without its word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    /// `:-` / `-`
+    Default,
+    /// `:?` / `?`
+    Error,
+    /// `:+` / `+`
+    Alternate,
+    /// `:=` / `=`
+    Assign,
+    /// `:~`
+    Substring,
+}
+
+impl<'a> Op<'a> {
+    /// The kind of the operator.
+    pub fn kind(&self) -> OpKind {
+        match self {
+            Op::Default { .. } => OpKind::Default,
+            Op::Error { .. } => OpKind::Error,
+            Op::Alternate { .. } => OpKind::Alternate,
+            Op::Assign { .. } => OpKind::Assign,
+            Op::Substring { .. } => OpKind::Substring,
+        }
+    }
+
+    /// Whether the operator has a leading colon, i.e. also treats an empty value as unset.
+    pub fn colon(&self) -> bool {
+        match self {
+            Op::Default { colon, .. }
+            | Op::Error { colon, .. }
+            | Op::Alternate { colon, .. }
+            | Op::Assign { colon, .. } => *colon,
+            Op::Substring { .. } => false,
+        }
+    }
+
+    /// The word after the operator. Empty for [`Op::Substring`].
+    pub fn word(&self) -> &[Node<'a>] {
+        match self {
+            Op::Default { word, .. }
+            | Op::Error { word, .. }
+            | Op::Alternate { word, .. }
+            | Op::Assign { word, .. } => word,
+            Op::Substring { .. } => &[],
+        }
+    }
+
+    /// Mutable access to the word after the operator, if it has one.
+    pub fn word_mut(&mut self) -> Option<&mut Vec<Node<'a>>> {
+        match self {
+            Op::Default { word, .. }
+            | Op::Error { word, .. }
+            | Op::Alternate { word, .. }
+            | Op::Assign { word, .. } => Some(word),
+            Op::Substring { .. } => None,
+        }
+    }
+
+    /// The operator's spelling without its word, e.g. `:-`.
+    pub fn symbol(&self) -> &'static str {
+        match (self, self.colon()) {
+            (Op::Default { .. }, true) => ":-",
+            (Op::Default { .. }, false) => "-",
+            (Op::Error { .. }, true) => ":?",
+            (Op::Error { .. }, false) => "?",
+            (Op::Alternate { .. }, true) => ":+",
+            (Op::Alternate { .. }, false) => "+",
+            (Op::Assign { .. }, true) => ":=",
+            (Op::Assign { .. }, false) => "=",
+            (Op::Substring { .. }, _) => ":~",
+        }
+    }
+
+    /// Parses the operator at the start of `bytes`, returning its character, whether it has a
+    /// leading colon and its length in bytes.
+    fn parse(bytes: &[u8]) -> Option<(char, bool, usize)> {
+        let colon = *bytes.first()? == b':';
+        let c = char::from(*bytes.get(colon as usize)?);
+        matches!(c, '-' | '?' | '+' | '=').then_some((c, colon, colon as usize + 1))
+    }
+
+    /// Parses the `start[,length]` of a substring. Like cmd.exe, numbers may have a `+` sign
+    /// or leading zeros, and ones too large for an `i64` are clamped.
+    fn substring(spec: &'a str) -> Option<Op<'a>> {
+        fn int(s: &str) -> Option<i64> {
+            let digits = s.strip_prefix(['+', '-']).unwrap_or(s);
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let negative = s.starts_with('-');
+            Some(
+                s.parse()
+                    .unwrap_or(if negative { i64::MIN } else { i64::MAX }),
+            )
+        }
+        let (start, length) = match spec.split_once(',') {
+            Some((start, length)) => (int(start)?, Some(int(length)?)),
+            None => (int(spec)?, None),
+        };
+        Some(Op::Substring {
+            start,
+            length,
+            spec: Cow::Borrowed(spec),
+        })
+    }
+
+    fn new(c: char, colon: bool, word: Vec<Node<'a>>) -> Op<'a> {
+        match c {
+            '-' => Op::Default { colon, word },
+            '?' => Op::Error { colon, word },
+            '+' => Op::Alternate { colon, word },
+            _ => Op::Assign { colon, word },
+        }
+    }
+
+    /// Copies the borrowed parts of the operator, so it no longer borrows from the input.
+    pub fn into_owned(self) -> Op<'static> {
+        let owned = |word: Vec<Node<'a>>| word.into_iter().map(Node::into_owned).collect();
+        match self {
+            Op::Default { colon, word } => Op::Default {
+                colon,
+                word: owned(word),
+            },
+            Op::Error { colon, word } => Op::Error {
+                colon,
+                word: owned(word),
+            },
+            Op::Alternate { colon, word } => Op::Alternate {
+                colon,
+                word: owned(word),
+            },
+            Op::Assign { colon, word } => Op::Assign {
+                colon,
+                word: owned(word),
+            },
+            Op::Substring {
+                start,
+                length,
+                spec,
+            } => Op::Substring {
+                start,
+                length,
+                spec: Cow::Owned(spec.into_owned()),
+            },
+        }
+    }
+}
+
+impl Node<'_> {
+    /// Copies the borrowed parts of the node, so it no longer borrows from the input.
+    pub fn into_owned(self) -> Node<'static> {
+        let owned = |s: Cow<'_, str>| Cow::Owned(s.into_owned());
+        match self {
+            Node::Text { text, span } => Node::Text {
+                text: owned(text),
+                span,
+            },
+            Node::Escape { raw, span } => Node::Escape {
+                raw: owned(raw),
+                span,
+            },
+            Node::Var(var) => Node::Var(var.into_owned()),
+            Node::Tilde { user, span } => Node::Tilde {
+                user: owned(user),
+                span,
+            },
+        }
+    }
+}
+
+impl Var<'_> {
+    /// Copies the borrowed parts of the reference, so it no longer borrows from the input.
+    pub fn into_owned(self) -> Var<'static> {
+        let owned = |s: Cow<'_, str>| Cow::Owned(s.into_owned());
+        Var {
+            name: owned(self.name),
+            form: match self.form {
+                Form::Simple => Form::Simple,
+                Form::Braced => Form::Braced,
+                Form::Percent => Form::Percent,
+                Form::Custom { open, close } => Form::Custom {
+                    open: owned(open),
+                    close: owned(close),
+                },
+                Form::Paren => Form::Paren,
+            },
+            op: self.op.map(Op::into_owned),
+            span: self.span,
+        }
+    }
+}
+
+impl fmt::Display for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Text { text, .. } => f.write_str(text),
+            Node::Escape { raw, .. } => f.write_str(raw),
+            Node::Var(var) => var.fmt(f),
+            Node::Tilde { user, .. } => write!(f, "~{}", user),
+        }
+    }
+}
+
+impl fmt::Display for Var<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.form {
+            Form::Simple => write!(f, "${}", self.name),
+            Form::Braced => {
+                write!(f, "${{{}", self.name)?;
+                if let Some(op) = &self.op {
+                    f.write_str(op.symbol())?;
+                    op.word().iter().try_for_each(|node| node.fmt(f))?;
+                }
+                f.write_str("}")
+            }
+            Form::Percent => {
+                write!(f, "%{}", self.name)?;
+                if let Some(Op::Substring { spec, .. }) = &self.op {
+                    write!(f, ":~{}", spec)?;
+                }
+                f.write_str("%")
+            }
+            Form::Custom { open, close } => write!(f, "{}{}{}", open, self.name, close),
+            Form::Paren => write!(f, "$({})", self.name),
+        }
+    }
+}
+
+/// Parses `input` into nodes using the syntax and escapes of `options`.
+///
+/// # Errors
+///
+/// Returns [`EnvExpansionError::Parse`] for malformed placeholders if
+/// [`Options::strict_parse`] is enabled; otherwise they are kept as literal text.
+pub fn parse<'a>(input: &'a str, options: &Options) -> Result<Vec<Node<'a>>, EnvExpansionError> {
+    Parser::new(input.as_bytes(), options).parse(input, 0)
+}
+
+/// Like [`parse`], but hands each top-level node to `emit` as soon as it is parsed instead of
+/// collecting them, stopping at the first error `emit` returns.
+pub(crate) fn parse_each<'a>(
+    input: &'a str,
+    options: &Options,
+    emit: &mut impl FnMut(Node<'a>) -> Result<(), EnvExpansionError>,
+) -> Result<(), EnvExpansionError> {
+    Parser::new(input.as_bytes(), options).parse_each(input, 0, emit)
+}
+
+/// A piece of input that need not be valid Unicode.
+pub(crate) enum Part<'a> {
+    /// A node parsed from a valid Unicode part of the input.
+    Node(Node<'a>),
+    /// Bytes that are not valid Unicode, copied through unchanged.
+    Raw(&'a OsStr),
+}
+
+/// Like [`parse`], but for input that need not be valid Unicode. See [`parse_each_os`].
+pub(crate) fn parse_os<'a>(
+    input: &'a OsStr,
+    options: &Options,
+) -> Result<Vec<Part<'a>>, EnvExpansionError> {
+    let mut parts = Vec::new();
+    parse_each_os(input, options, &mut |part| {
+        parts.push(part);
+        Ok(())
+    })?;
+    Ok(parts)
+}
+
+/// Like [`parse_each`], but for input that need not be valid Unicode. Placeholders are only
+/// recognized within its valid Unicode parts, and the bytes between them are passed on as
+/// [`Part::Raw`]. Spans are byte offsets into all of `input`.
+pub(crate) fn parse_each_os<'a>(
+    input: &'a OsStr,
+    options: &Options,
+    emit: &mut impl FnMut(Part<'a>) -> Result<(), EnvExpansionError>,
+) -> Result<(), EnvExpansionError> {
+    let bytes = input.as_encoded_bytes();
+    let parser = Parser::new(bytes, options);
+    let mut chunks = bytes.utf8_chunks().peekable();
+    let mut base = 0;
+    while let Some(chunk) = chunks.next() {
+        let valid = chunk.valid();
+        parser.parse_each(valid, base, &mut |node| emit(Part::Node(node)))?;
+        base += valid.len();
+        // Runs of invalid bytes are only split next to valid text, as `OsStr` requires
+        let start = base;
+        base += chunk.invalid().len();
+        while let Some(next) = chunks.next_if(|next| next.valid().is_empty()) {
+            base += next.invalid().len();
+        }
+        if base > start {
+            // SAFETY: the bytes come from `as_encoded_bytes`, and the run starts at the start
+            // of `input` or after valid non-empty UTF-8, and ends at the end of `input` or
+            // before valid non-empty UTF-8.
+            emit(Part::Raw(unsafe {
+                OsStr::from_encoded_bytes_unchecked(&bytes[start..base])
+            }))?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses the value of a variable for recursive expansion. Malformed placeholders in values are
+/// always kept as literal text, since errors could not be located in the template.
+pub(crate) fn parse_value<'a>(input: &'a str, options: &Options) -> Vec<Node<'a>> {
+    Parser {
+        strict: false,
+        ..Parser::new(input.as_bytes(), options)
+    }
+    .parse(input, 0)
+    .expect("lenient parsing never fails")
+}
+
+/// Writes `nodes` back out as template source.
+pub fn to_source(nodes: &[Node<'_>]) -> String {
+    nodes.iter().map(Node::to_string).collect()
+}
+
+/// Calls `f` on every node in pre-order, including the nodes inside operator words.
+pub fn walk<'n, 'a>(nodes: &'n [Node<'a>], f: &mut impl FnMut(&'n Node<'a>)) {
+    for node in nodes {
+        f(node);
+        if let Node::Var(Var { op: Some(op), .. }) = node {
+            walk(op.word(), f);
+        }
+    }
+}
+
+/// Like [`walk`], but allows `f` to modify the nodes. Changes to a node are visible when its
+/// children are visited.
+pub fn walk_mut<'a>(nodes: &mut [Node<'a>], f: &mut impl FnMut(&mut Node<'a>)) {
+    for node in nodes {
+        f(node);
+        if let Node::Var(Var { op: Some(op), .. }) = node
+            && let Some(word) = op.word_mut()
+        {
+            walk_mut(word, f);
+        }
+    }
+}
+
+struct Parser<'a> {
+    /// The whole input, to locate errors.
+    source: &'a [u8],
+    syntax: &'a Syntax,
+    escape: Escape,
+    strict: bool,
+    tilde: bool,
+}
+
+impl<'p> Parser<'p> {
+    fn new(source: &'p [u8], options: &'p Options) -> Self {
+        Parser {
+            source,
+            syntax: &options.syntax,
+            escape: options.escape,
+            strict: options.strict_parse,
+            tilde: options.tilde,
+        }
+    }
+
+    fn error(&self, kind: ParseErrorKind, span: Span) -> EnvExpansionError {
+        EnvExpansionError::Parse {
+            kind,
+            location: Location::in_bytes(self.source, span),
+        }
+    }
+
+    /// Parses `input`, which starts at offset `base` in the original input.
+    fn parse<'i>(&self, input: &'i str, base: usize) -> Result<Vec<Node<'i>>, EnvExpansionError> {
+        let mut nodes = Vec::new();
+        self.parse_each(input, base, &mut |node| {
+            nodes.push(node);
+            Ok(())
+        })?;
+        Ok(nodes)
+    }
+
+    /// Parses `input`, which starts at offset `base` in the original input, passing each node
+    /// to `emit`.
+    ///
+    /// Placeholders and escapes start with an ASCII byte or the first byte of a custom
+    /// delimiter, so the scan jumps between candidates with `memchr` and only ever stops on
+    /// char boundaries; the literal text in between is copied as whole slices.
+    fn parse_each<'i>(
+        &self,
+        input: &'i str,
+        base: usize,
+        emit: &mut impl FnMut(Node<'i>) -> Result<(), EnvExpansionError>,
+    ) -> Result<(), EnvExpansionError> {
+        let bytes = input.as_bytes();
+        let span = |start: usize, end: usize| Span {
+            start: base + start,
+            end: base + end,
+        };
+        let (dollar, percent) = (self.syntax.dollar(), self.syntax.percent());
+        let paren = self.syntax.paren();
+        let compose = matches!(self.syntax, Syntax::Compose);
+        let escape = self.escape;
+        // Start of the pending run of literal text
+        let mut text = 0;
+        let mut i = 0;
+
+        while let Some(at) = self.next_candidate(bytes, i) {
+            i = at;
+            let next = bytes.get(i + 1).copied();
+            let (node, end) = match (bytes[i], next) {
+                (b'$', Some(b'$')) if dollar && escape.double() => {
+                    (escape_node(input, span(i, i + 2), base), i + 2)
+                }
+                (b'%', Some(b'%')) if percent && escape.double() => {
+                    (escape_node(input, span(i, i + 2), base), i + 2)
+                }
+                (b'\\', Some(b'$')) if dollar && escape.backslash() => {
+                    (escape_node(input, span(i, i + 2), base), i + 2)
+                }
+                (b'$', Some(b'$')) if paren => (escape_node(input, span(i, i + 2), base), i + 2),
+                (b'$', Some(b'(')) if paren => {
+                    // Handle $(VAR); the name is everything up to the closing parenthesis
+                    let Some(j) = memchr(b')', &bytes[i + 2..]).map(|n| i + 2 + n) else {
+                        if self.strict {
+                            let kind = ParseErrorKind::Unterminated;
+                            return Err(self.error(kind, span(i, bytes.len())));
+                        }
+                        // No closing parenthesis, treat as literal
+                        i += 1;
+                        continue;
+                    };
+                    if self.strict && j == i + 2 {
+                        return Err(self.error(ParseErrorKind::EmptyName, span(i, j + 1)));
+                    }
+                    let var = Var {
+                        name: Cow::Borrowed(&input[i + 2..j]),
+                        form: Form::Paren,
+                        op: None,
+                        span: span(i, j + 1),
+                    };
+                    (Node::Var(var), j + 1)
+                }
+                (b'$', Some(b'{')) if dollar => {
+                    // Handle ${VAR} and ${VAR<op>word}
+                    let Some(j) = closing_brace(bytes, i + 2, escape) else {
+                        if self.strict {
+                            let kind = ParseErrorKind::Unterminated;
+                            return Err(self.error(kind, span(i, bytes.len())));
+                        }
+                        // No closing brace, treat as literal
+                        i += 1;
+                        continue;
+                    };
+
+                    let k = match bytes[i + 2] {
+                        // Compose names cannot start with a digit
+                        b'0'..=b'9' if compose => i + 2,
+                        _ => i + 2 + name_len(&bytes[i + 2..j]),
+                    };
+                    let op = Op::parse(&bytes[k..j]).filter(|&(c, ..)| !(compose && c == '='));
+                    if self.strict && k == i + 2 && (j == k || op.is_some()) {
+                        return Err(self.error(ParseErrorKind::EmptyName, span(i, j + 1)));
+                    }
+                    if self.strict && k < j && op.is_none() {
+                        let name = input[i + 2..j].to_string();
+                        let kind = ParseErrorKind::InvalidName(name);
+                        return Err(self.error(kind, span(i, j + 1)));
+                    }
+
+                    let var = match op {
+                        Some((c, colon, len)) => {
+                            let word_start = k + len;
+                            let word = self.parse(&input[word_start..j], base + word_start)?;
+                            Var {
+                                name: Cow::Borrowed(&input[i + 2..k]),
+                                form: Form::Braced,
+                                op: Some(Op::new(c, colon, word)),
+                                span: span(i, j + 1),
+                            }
+                        }
+                        _ => Var {
+                            name: Cow::Borrowed(&input[i + 2..j]),
+                            form: Form::Braced,
+                            op: None,
+                            span: span(i, j + 1),
+                        },
+                    };
+                    (Node::Var(var), j + 1)
+                }
+                (b'$', Some(c))
+                    if dollar && is_name_byte(c) && !(compose && c.is_ascii_digit()) =>
+                {
+                    // Handle $VAR
+                    let j = i + 1 + name_len(&bytes[i + 1..]);
+                    let var = Var {
+                        name: Cow::Borrowed(&input[i + 1..j]),
+                        form: Form::Simple,
+                        op: None,
+                        span: span(i, j),
+                    };
+                    (Node::Var(var), j)
+                }
+                (b'%', _) if percent => {
+                    // Handle %VAR%
+                    let Some(j) = memchr(b'%', &bytes[i + 1..]).map(|n| i + 1 + n) else {
+                        if self.strict {
+                            let kind = ParseErrorKind::Unterminated;
+                            return Err(self.error(kind, span(i, bytes.len())));
+                        }
+                        // No closing %, treat as literal
+                        i += 1;
+                        continue;
+                    };
+                    // Handle %VAR:~start,length%
+                    let content = &input[i + 1..j];
+                    let substring = content
+                        .split_once(":~")
+                        .and_then(|(name, spec)| Some((name, Op::substring(spec)?)));
+                    let (name, op) = match substring {
+                        Some((name, op)) => (name, Some(op)),
+                        None => (content, None),
+                    };
+                    if self.strict && name.is_empty() {
+                        return Err(self.error(ParseErrorKind::EmptyName, span(i, j + 1)));
+                    }
+                    if self.strict && op.is_none() && content.contains(":~") {
+                        let kind = ParseErrorKind::InvalidName(content.to_string());
+                        return Err(self.error(kind, span(i, j + 1)));
+                    }
+                    let var = Var {
+                        name: Cow::Borrowed(name),
+                        form: Form::Percent,
+                        op,
+                        span: span(i, j + 1),
+                    };
+                    (Node::Var(var), j + 1)
+                }
+                (b'~', _) if self.tilde && (i == 0 || is_word_break(bytes[i - 1])) => {
+                    // Handle ~, ~+, ~- and ~user at the start of a word
+                    let j = bytes[i + 1..]
+                        .iter()
+                        .position(|&b| b == b'/' || is_word_break(b))
+                        .map_or(bytes.len(), |n| i + 1 + n);
+                    let user = &input[i + 1..j];
+                    if !matches!(user, "+" | "-") && !user.bytes().all(is_user_byte) {
+                        i += 1;
+                        continue;
+                    }
+                    let node = Node::Tilde {
+                        user: Cow::Borrowed(user),
+                        span: span(i, j),
+                    };
+                    (node, j)
+                }
+                _ => {
+                    // Handle custom delimiters; anything else, including a `$` not followed by
+                    // a name, is literal
+                    let Some(var) = self.custom(input, i, base)? else {
+                        i += 1;
+                        continue;
+                    };
+                    let end = var.span.end - base;
+                    (Node::Var(var), end)
+                }
+            };
+            if let Node::Var(var) = &node
+                && var.name.is_empty()
+            {
+                // Without a name, as in `${}`, `$()` or `%%`, a placeholder is literal text
+                i = end;
+                continue;
+            }
+
+            emit_text(emit, input, text, i, base)?;
+            emit(node)?;
+            i = end;
+            text = i;
+        }
+
+        emit_text(emit, input, text, input.len(), base)
+    }
+
+    /// Finds the first byte at or after `from` that may start a placeholder or an escape.
+    fn next_candidate(&self, bytes: &[u8], from: usize) -> Option<usize> {
+        let haystack = &bytes[from..];
+        if self.tilde {
+            // Too many needles for memchr, and tildes are rare enough to stop at every one
+            let tilde = memchr(b'~', haystack);
+            let other = Parser {
+                tilde: false,
+                ..*self
+            }
+            .next_candidate(bytes, from)
+            .map(|at| at - from);
+            return match (tilde, other) {
+                (Some(a), Some(b)) => Some(from + a.min(b)),
+                (a, b) => a.or(b).map(|n| from + n),
+            };
+        }
+        let backslash = self.escape.backslash();
+        let found = match self.syntax {
+            Syntax::Custom { open, .. } if open.is_empty() => None,
+            Syntax::Custom { open, .. } => memmem::find(haystack, open.as_bytes()),
+            Syntax::Unix | Syntax::Compose if backslash => memchr2(b'$', b'\\', haystack),
+            Syntax::Unix | Syntax::Compose | Syntax::Kubernetes => memchr(b'$', haystack),
+            Syntax::Windows => memchr(b'%', haystack),
+            Syntax::Both if backslash => memchr3(b'$', b'%', b'\\', haystack),
+            Syntax::Both => memchr2(b'$', b'%', haystack),
+        };
+        found.map(|n| from + n)
+    }
+
+    /// Matches a custom-delimited reference at byte offset `at`.
+    fn custom<'i>(
+        &self,
+        input: &'i str,
+        at: usize,
+        base: usize,
+    ) -> Result<Option<Var<'i>>, EnvExpansionError> {
+        let Syntax::Custom { open, close } = self.syntax else {
+            return Ok(None);
+        };
+        if open.is_empty() || !input[at..].starts_with(open.as_str()) {
+            return Ok(None);
+        }
+        let name_start = at + open.len();
+        let span = |end: usize| Span {
+            start: base + at,
+            end: base + end,
+        };
+        let Some(len) = input[name_start..].find(close.as_str()) else {
+            if self.strict {
+                let kind = ParseErrorKind::Unterminated;
+                return Err(self.error(kind, span(input.len())));
+            }
+            return Ok(None);
+        };
+        let end = name_start + len + close.len();
+        if self.strict && len == 0 {
+            return Err(self.error(ParseErrorKind::EmptyName, span(end)));
+        }
+        // The delimiters are spelled the same in the input, so they can be borrowed from it
+        Ok(Some(Var {
+            name: Cow::Borrowed(&input[name_start..name_start + len]),
+            form: Form::Custom {
+                open: Cow::Borrowed(&input[at..name_start]),
+                close: Cow::Borrowed(&input[name_start + len..end]),
+            },
+            op: None,
+            span: span(end),
+        }))
+    }
+}
+
+fn escape_node(input: &str, span: Span, base: usize) -> Node<'_> {
+    Node::Escape {
+        raw: Cow::Borrowed(&input[span.start - base..span.end - base]),
+        span,
+    }
+}
+
+fn emit_text<'i>(
+    emit: &mut impl FnMut(Node<'i>) -> Result<(), EnvExpansionError>,
+    input: &'i str,
+    start: usize,
+    end: usize,
+    base: usize,
+) -> Result<(), EnvExpansionError> {
+    if start == end {
+        return Ok(());
+    }
+    emit(Node::Text {
+        text: Cow::Borrowed(&input[start..end]),
+        span: Span {
+            start: base + start,
+            end: base + end,
+        },
+    })
+}
+
+/// Whether a `~` after `b` starts a word: after whitespace, or after the `=` of an assignment
+/// or a `:` as in `PATH=~/bin:~/.local/bin`.
+fn is_word_break(b: u8) -> bool {
+    b.is_ascii_whitespace() || b == b':' || b == b'='
+}
+
+/// Whether `b` may appear in a user name after `~`.
+fn is_user_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-')
+}
+
+fn is_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// Length of the name at the start of `bytes`.
+fn name_len(bytes: &[u8]) -> usize {
+    bytes.iter().take_while(|&&b| is_name_byte(b)).count()
+}
+
+/// Finds the `}` closing a `${` whose contents start at `from`, skipping nested `${...}`.
+fn closing_brace(bytes: &[u8], from: usize, escape: Escape) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = from;
+    while let Some(n) = memchr3(b'$', b'\\', b'}', &bytes[i..]) {
+        i += n;
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'$', Some(b'$')) if escape.double() => i += 1,
+            (b'\\', Some(b'$')) if escape.backslash() => i += 1,
+            (b'$', Some(b'{')) => {
+                depth += 1;
+                i += 1;
+            }
+            (b'}', _) if depth == 0 => return Some(i),
+            (b'}', _) => depth -= 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix() -> Options {
+        Options::new().syntax(Syntax::Unix)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let inputs = [
+            "plain text",
+            "$A ${B} ${C:-x} ${D-$E} ${F:?oops ${G}} ${H:+y} ${I:=z} ${J K}",
+            "unclosed ${A:-x and $ bare $. and trailing $",
+            "escaped $$A \\$B ${C:-$${D}}",
+        ];
+        let options = unix().escape(Escape::Both);
+        for input in inputs {
+            let nodes = parse(input, &options).unwrap();
+            assert_eq!(to_source(&nodes), input);
+            let owned: Vec<Node<'static>> = nodes.iter().cloned().map(Node::into_owned).collect();
+            assert_eq!(owned, nodes);
+        }
+
+        let windows = Options::new()
+            .syntax(Syntax::Windows)
+            .escape(Escape::Double);
+        let input = "%A% 100%% %B";
+        assert_eq!(to_source(&parse(input, &windows).unwrap()), input);
+
+        let custom = Options::new().syntax(Syntax::Custom {
+            open: "{{".to_string(),
+            close: "}}".to_string(),
+        });
+        let input = "{{A}} {{B";
+        assert_eq!(to_source(&parse(input, &custom).unwrap()), input);
+    }
+
+    #[test]
+    fn test_spans() {
+        let nodes = parse("a${B:-$C}d", &unix()).unwrap();
+        let Node::Var(var) = &nodes[1] else {
+            panic!("expected a variable, got {:?}", nodes[1]);
+        };
+        assert_eq!(var.span, Span { start: 1, end: 9 });
+        assert_eq!(var.form, Form::Braced);
+        let op = var.op.as_ref().unwrap();
+        assert_eq!(op.symbol(), ":-");
+        assert!(
+            matches!(&op.word()[0], Node::Var(Var { name, span: Span { start: 6, end: 8 }, .. }) if name == "C")
+        );
+        assert_eq!(
+            nodes[2],
+            Node::Text {
+                text: "d".into(),
+                span: Span { start: 9, end: 10 },
+            }
+        );
+    }
+
+    #[test]
+    fn test_multibyte_text() {
+        let nodes = parse("é$Aü${B:-ñ}€", &unix()).unwrap();
+        assert_eq!(nodes.len(), 5);
+        assert_eq!(nodes[1].to_string(), "$A");
+        assert!(matches!(&nodes[3], Node::Var(var) if var.span == Span { start: 6, end: 14 }));
+        assert!(matches!(&nodes[4], Node::Text { text, .. } if text == "€"));
+
+        let custom = Options::new().syntax(Syntax::Custom {
+            open: "«".to_string(),
+            close: "»".to_string(),
+        });
+        let nodes = parse("a«X»b«", &custom).unwrap();
+        assert_eq!(nodes.len(), 3);
+        assert!(matches!(&nodes[1], Node::Var(var) if var.name == "X"));
+        assert!(matches!(&nodes[2], Node::Text { text, .. } if text == "b«"));
+    }
+
+    #[test]
+    fn test_tilde() {
+        let options = unix().tilde(true);
+        let input = "~ ~/x a~b PATH=/usr:~+:~-:~root/bin ~$X ~a b:~ PATH=~/bin:/bin a=~b";
+        let nodes = parse(input, &options).unwrap();
+        let users: Vec<_> = nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::Tilde { user, .. } => Some(user.as_ref()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(users, ["", "", "+", "-", "root", "a", "", "", "b"]);
+        assert_eq!(to_source(&nodes), input);
+        assert!(
+            parse(input, &unix())
+                .unwrap()
+                .iter()
+                .all(|node| !matches!(node, Node::Tilde { .. }))
+        );
+    }
+
+    #[test]
+    fn test_kubernetes() {
+        let options = Options::new().syntax(Syntax::Kubernetes);
+        let input = "$(A)-$$(B)-$(C D)-$(-$x-${E}-$";
+        let nodes = parse(input, &options).unwrap();
+        assert_eq!(to_source(&nodes), input);
+        let vars: Vec<_> = nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::Var(var) => Some((var.name.as_ref(), var.span)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vars,
+            [
+                ("A", Span { start: 0, end: 4 }),
+                ("C D", Span { start: 11, end: 17 })
+            ]
+        );
+        assert!(matches!(&nodes[2], Node::Escape { raw, .. } if raw == "$$"));
+
+        let strict = options.strict_parse(true);
+        assert!(parse("$(A", &strict).is_err());
+        assert!(parse("$()", &strict).is_err());
+    }
+
+    #[test]
+    fn test_substring() {
+        let windows = Options::new().syntax(Syntax::Windows);
+        let input = "%PATH:~0,10% %A:~-3% %B:~2,-1% %C:~x% %D:~007% %E:~+3,-0% %F:~+% %G:~99999999999999999999%";
+        let nodes = parse(input, &windows).unwrap();
+        assert_eq!(to_source(&nodes), input);
+        let ops: Vec<_> = nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::Var(var) => Some((
+                    var.name.as_ref(),
+                    var.op.as_ref().map(|op| match op {
+                        Op::Substring { start, length, .. } => (*start, *length),
+                        _ => unreachable!(),
+                    }),
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                ("PATH", Some((0, Some(10)))),
+                ("A", Some((-3, None))),
+                ("B", Some((2, Some(-1)))),
+                ("C:~x", None),
+                ("D", Some((7, None))),
+                ("E", Some((3, Some(0)))),
+                ("F:~+", None),
+                ("G", Some((i64::MAX, None))),
+            ]
+        );
+        assert!(parse("%C:~x%", &windows.strict_parse(true)).is_err());
+    }
+
+    #[test]
+    fn test_walk() {
+        let nodes = parse("$A ${B:-${C:+$D}}", &unix()).unwrap();
+        let mut names = Vec::new();
+        walk(&nodes, &mut |node| {
+            if let Node::Var(var) = node {
+                names.push(var.name.as_ref());
+            }
+        });
+        assert_eq!(names, ["A", "B", "C", "D"]);
+    }
+
+    #[test]
+    fn test_strict_parse() {
+        let strict = unix().strict_parse(true);
+        let kind = |input: &str| match parse(input, &strict) {
+            Err(EnvExpansionError::Parse { kind, location }) => (kind, location.span),
+            other => panic!("expected a parse error for {input:?}, got {other:?}"),
+        };
+        assert_eq!(
+            kind("a ${HOME/bin"),
+            (ParseErrorKind::Unterminated, Span { start: 2, end: 12 })
+        );
+        assert_eq!(
+            kind("${}"),
+            (ParseErrorKind::EmptyName, Span { start: 0, end: 3 })
+        );
+        assert_eq!(kind("${:-x}").0, ParseErrorKind::EmptyName);
+        assert_eq!(
+            kind("${A:-${B C}}"),
+            (
+                ParseErrorKind::InvalidName("B C".to_string()),
+                Span { start: 5, end: 11 }
+            )
+        );
+        assert!(parse("$ ${A:-x} $A", &strict).is_ok());
+        assert!(parse("a ${HOME/bin ${} ${B C}", &unix()).is_ok());
+
+        let windows = Options::new().syntax(Syntax::Windows).strict_parse(true);
+        assert!(matches!(
+            parse("%A% 100%", &windows),
+            Err(EnvExpansionError::Parse {
+                kind: ParseErrorKind::Unterminated,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("%%", &windows),
+            Err(EnvExpansionError::Parse {
+                kind: ParseErrorKind::EmptyName,
+                ..
+            })
+        ));
+        assert!(parse("100%%", &windows.escape(Escape::Double)).is_ok());
+    }
+}
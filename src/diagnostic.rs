@@ -0,0 +1,135 @@
+//! Source locations for errors.
+
+use std::fmt::Write;
+
+use memchr::{memchr, memchr_iter, memrchr};
+
+use crate::Span;
+
+/// Where in the input an error occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// Byte range of the offending placeholder.
+    pub span: Span,
+    /// 1-based line number of `span.start`.
+    pub line: usize,
+    /// 1-based column of `span.start`, counted in characters.
+    pub column: usize,
+    /// The full line containing `span.start`, without its line ending.
+    pub snippet: String,
+}
+
+impl Location {
+    /// Computes the location of `span` in `input`.
+    pub fn new(input: &str, span: Span) -> Self {
+        Self::in_bytes(input.as_bytes(), span)
+    }
+
+    /// Like [`Location::new`], but for input that need not be valid Unicode. Columns and the
+    /// snippet count each invalid sequence as one replacement character.
+    pub(crate) fn in_bytes(input: &[u8], span: Span) -> Self {
+        let before = &input[..span.start];
+        let line_start = memrchr(b'\n', before).map_or(0, |i| i + 1);
+        let line_end = memchr(b'\n', &input[span.start..]).map_or(input.len(), |i| span.start + i);
+        Self {
+            span,
+            line: memchr_iter(b'\n', before).count() + 1,
+            column: String::from_utf8_lossy(&before[line_start..])
+                .chars()
+                .count()
+                + 1,
+            snippet: String::from_utf8_lossy(&input[line_start..line_end])
+                .trim_end_matches('\r')
+                .to_string(),
+        }
+    }
+
+    /// Byte offset of the start of the placeholder.
+    pub fn offset(&self) -> usize {
+        self.span.start
+    }
+
+    /// Renders `message` with the snippet and a caret under the placeholder:
+    ///
+    /// ```text
+    /// error: Missing environment variable: HOME
+    ///  --> 2:7
+    ///   |
+    /// 2 | path: ${HOME}/bin
+    ///   |       ^^^^^^^
+    /// ```
+    pub fn render(&self, message: &str) -> String {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        // Keep tabs so the caret lines up with the snippet however they are displayed
+        let prefix: String = self
+            .snippet
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = caret_width(&self.snippet, self.column - 1, self.span);
+
+        let mut out = String::new();
+        let _ = writeln!(out, "error: {message}");
+        let _ = writeln!(out, "{gutter}--> {}:{}", self.line, self.column);
+        let _ = writeln!(out, "{gutter} |");
+        let _ = writeln!(out, "{number} | {}", self.snippet);
+        let _ = write!(out, "{gutter} | {prefix}{}", "^".repeat(width));
+        out
+    }
+}
+
+/// Number of chars of `span` that fall on the snippet line, which `span` enters at char
+/// `column`. Always at least one so empty spans still get a caret.
+fn caret_width(snippet: &str, column: usize, span: Span) -> usize {
+    let mut bytes = 0;
+    let mut chars = 0;
+    for c in snippet.chars().skip(column) {
+        if bytes >= span.end - span.start {
+            break;
+        }
+        bytes += c.len_utf8();
+        chars += 1;
+    }
+    chars.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        let input = "first\nsecond ${VAR}\r\nthird";
+        let location = Location::new(input, Span { start: 13, end: 19 });
+        assert_eq!(location.offset(), 13);
+        assert_eq!(location.line, 2);
+        assert_eq!(location.column, 8);
+        assert_eq!(location.snippet, "second ${VAR}");
+    }
+
+    #[test]
+    fn test_render() {
+        let input = "a: 1\npath: ${HOME}/bin\n";
+        let location = Location::new(input, Span { start: 11, end: 18 });
+        assert_eq!(
+            location.render("Missing environment variable: HOME"),
+            "error: Missing environment variable: HOME\n \
+             --> 2:7\n  \
+               |\n\
+             2 | path: ${HOME}/bin\n  \
+               |       ^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_render_multibyte_and_multiline() {
+        let input = "é\t$X ${A:-\nmore}";
+        let location = Location::new(input, Span { start: 3, end: 5 });
+        assert!(location.render("m").ends_with("| \u{20}\t^^"));
+        let location = Location::new(input, Span { start: 6, end: 17 });
+        assert!(location.render("m").ends_with("^^^^^"));
+    }
+}
@@ -1,171 +1,1056 @@
 //! A cross-platform environment variable expander that supports Unix-style (`$VAR`, `${VAR}`)
 //! and Windows-style (`%VAR%`) syntax.
+//!
+//! [`expand_env_vars`] uses the syntax of the platform it was compiled for; use
+//! [`Options::syntax`] to pick one at runtime.
 
-use std::env;
-
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub mod ast;
+mod diagnostic;
+mod path;
+mod resolve;
+mod source;
+mod template;
+
+pub use diagnostic::Location;
+pub use source::{Env, FromFn, Layered, VarSource, from_fn};
+pub use template::Template;
 
 /// Custom error type for environment variable expansion.
 #[derive(Debug)]
 pub enum EnvExpansionError {
-    MissingVar(String),
+    MissingVar {
+        name: String,
+        location: Location,
+    },
+    /// Every unset variable referenced by the input, in order of appearance.
+    MissingVars(Vec<Missing>),
+    /// A variable marked as required with `${VAR:?message}` or `${VAR?message}` was unset
+    /// (or empty, with the colon).
+    Required {
+        name: String,
+        message: String,
+        location: Location,
+    },
+    /// A variable whose value is not valid Unicode, which the `String` API cannot represent.
+    /// Use [`expand_env_vars_os`] to expand such values.
+    NotUnicode {
+        name: String,
+        value: OsString,
+        location: Location,
+    },
+    /// Variables whose values refer to each other in a loop, found by [`Options::recursive`].
+    /// `chain` lists the variables from the first one in the loop back to itself.
+    Cycle {
+        chain: Vec<String>,
+        location: Location,
+    },
+    /// Problems that kept [`Options::resolve`] from expanding a set of variables: the cycles
+    /// among them, each listed from its first variable back to itself, and references to
+    /// variables that are set nowhere.
+    Unresolvable {
+        cycles: Vec<Vec<String>>,
+        unresolved: Vec<Unresolved>,
+    },
+    /// A malformed placeholder, reported when [`Options::strict_parse`] is enabled.
+    Parse {
+        kind: ParseErrorKind,
+        location: Location,
+    },
+}
+
+/// What is wrong with a placeholder rejected by [`Options::strict_parse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// A `${`, `%` or custom opening delimiter without its closing counterpart.
+    Unterminated,
+    /// A placeholder without a name, such as `${}` or `%%`.
+    EmptyName,
+    /// A braced placeholder whose contents are neither a name nor a name followed by an
+    /// operator, such as `${HOME/bin}`.
+    InvalidName(String),
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::Unterminated => write!(f, "Unterminated placeholder"),
+            ParseErrorKind::EmptyName => write!(f, "Empty variable name"),
+            ParseErrorKind::InvalidName(name) => write!(f, "Invalid variable name: {}", name),
+        }
+    }
+}
+
+impl EnvExpansionError {
+    /// Where the error occurred, if it relates to a single placeholder.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            EnvExpansionError::MissingVar { location, .. }
+            | EnvExpansionError::Required { location, .. }
+            | EnvExpansionError::NotUnicode { location, .. }
+            | EnvExpansionError::Cycle { location, .. }
+            | EnvExpansionError::Parse { location, .. } => Some(location),
+            EnvExpansionError::MissingVars(_) | EnvExpansionError::Unresolvable { .. } => None,
+        }
+    }
+
+    /// Renders the error for humans, quoting the offending line with a caret under each
+    /// placeholder. See [`Location::render`].
+    pub fn render(&self) -> String {
+        match self {
+            EnvExpansionError::MissingVars(missing) => missing
+                .iter()
+                .map(|m| {
+                    let message = format!("Missing environment variable: {}", m.name);
+                    m.location.render(&message)
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+            EnvExpansionError::Unresolvable { cycles, unresolved } => cycles
+                .iter()
+                .map(|cycle| {
+                    format!(
+                        "error: Cycle in variable references: {}",
+                        cycle.join(" -> ")
+                    )
+                })
+                .chain(unresolved.iter().map(|u| {
+                    let message = format!("{}: missing environment variable: {}", u.entry, u.name);
+                    u.location.render(&message)
+                }))
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+            _ => match self.location() {
+                Some(location) => location.render(&self.to_string()),
+                None => format!("error: {}", self),
+            },
+        }
+    }
 }
 
 impl fmt::Display for EnvExpansionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            EnvExpansionError::MissingVar(var) => {
-                write!(f, "Missing environment variable: {}", var)
+            EnvExpansionError::MissingVar { name, .. } => {
+                write!(f, "Missing environment variable: {}", name)
+            }
+            EnvExpansionError::MissingVars(missing) => {
+                write!(f, "Missing environment variables: ")?;
+                for (i, m) in missing.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    let Location { line, column, .. } = m.location;
+                    write!(f, "{} (line {}, column {})", m.name, line, column)?;
+                }
+                Ok(())
+            }
+            EnvExpansionError::Required { name, message, .. } if message.is_empty() => {
+                write!(f, "{}: parameter null or not set", name)
+            }
+            EnvExpansionError::Required { name, message, .. } => {
+                write!(f, "{}: {}", name, message)
+            }
+            EnvExpansionError::NotUnicode { name, .. } => {
+                write!(f, "Environment variable is not valid Unicode: {}", name)
             }
+            EnvExpansionError::Cycle { chain, .. } => {
+                write!(f, "Cycle in variable references: {}", chain.join(" -> "))
+            }
+            EnvExpansionError::Unresolvable { cycles, unresolved } => {
+                write!(f, "Unresolvable variables: ")?;
+                let cycles = cycles.iter().map(|c| format!("cycle {}", c.join(" -> ")));
+                let unresolved = unresolved
+                    .iter()
+                    .map(|u| format!("{} refers to unset {}", u.entry, u.name));
+                for (i, problem) in cycles.chain(unresolved).enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", problem)?;
+                }
+                Ok(())
+            }
+            EnvExpansionError::Parse { kind, .. } => kind.fmt(f),
         }
     }
 }
 
 impl std::error::Error for EnvExpansionError {}
 
+/// A reference to a variable that is set nowhere, reported by
+/// [`EnvExpansionError::Unresolvable`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unresolved {
+    /// The variable whose value contains the reference.
+    pub entry: String,
+    /// Name of the unset variable.
+    pub name: String,
+    /// Location of the reference in the value of `entry`.
+    pub location: Location,
+}
+
+/// A byte range in the expanded input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// An unset variable reported by [`EnvExpansionError::MissingVars`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Missing {
+    /// Name of the variable.
+    pub name: String,
+    /// Location of the placeholder that referenced it.
+    pub location: Location,
+}
+
+/// A variable referenced by an input, as returned by [`referenced_vars`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// Name of the variable.
+    pub name: String,
+    /// Location of the placeholder that references it.
+    pub span: Span,
+    /// The operator of a `${VAR<op>word}` reference.
+    pub op: Option<ast::OpKind>,
+}
+
+impl Reference {
+    /// Whether the reference supplies its own fallback with `${VAR:-default}` or
+    /// `${VAR:=default}`, so the variable may safely be unset.
+    pub fn has_default(&self) -> bool {
+        matches!(self.op, Some(ast::OpKind::Default | ast::OpKind::Assign))
+    }
+
+    /// Whether the reference marks the variable as required with `${VAR:?message}`.
+    pub fn is_required(&self) -> bool {
+        self.op == Some(ast::OpKind::Error)
+    }
+}
+
+/// How references to unset variables are treated during expansion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnMissing {
+    /// Replace the reference with an empty string.
+    #[default]
+    Empty,
+    /// Fail with [`EnvExpansionError::MissingVar`] on the first unset variable.
+    Error,
+    /// Keep scanning and fail with [`EnvExpansionError::MissingVars`] listing every unset
+    /// variable.
+    Collect,
+    /// Leave the reference in the output exactly as it was written, like Kubernetes does.
+    Keep,
+}
+
+/// Which escape sequences produce a literal sigil instead of starting a reference.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Escape {
+    /// No escapes; every sigil that starts a valid reference is expanded.
+    #[default]
+    None,
+    /// A doubled sigil is literal: `$$` produces `$` (as in Docker Compose and Make) and, on
+    /// Windows, `%%` produces `%`.
+    Double,
+    /// A backslash before `$` is literal: `\$` produces `$`, as in the shell. Other
+    /// backslashes are left alone.
+    Backslash,
+    /// Both [`Escape::Double`] and [`Escape::Backslash`].
+    Both,
+}
+
+impl Escape {
+    pub(crate) fn double(self) -> bool {
+        matches!(self, Escape::Double | Escape::Both)
+    }
+
+    pub(crate) fn backslash(self) -> bool {
+        matches!(self, Escape::Backslash | Escape::Both)
+    }
+}
+
+/// Which placeholder syntax to recognize.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Syntax {
+    /// `$VAR` and `${VAR}`, including the `${VAR<op>word}` forms.
+    Unix,
+    /// `%VAR%`.
+    Windows,
+    /// Both [`Syntax::Unix`] and [`Syntax::Windows`].
+    Both,
+    /// A name enclosed in custom delimiters, e.g. `{{VAR}}` or `@VAR@`.
+    Custom { open: String, close: String },
+    /// Kubernetes' `$(VAR)`, where `$$` is always an escape for `$`, so `$$(VAR)` produces
+    /// `$(VAR)`. Any other `$` is literal. Combine with [`OnMissing::Keep`] to leave
+    /// references to unset variables verbatim as Kubernetes does, or use
+    /// [`Options::kubernetes`].
+    Kubernetes,
+    /// Docker Compose's interpolation: like [`Syntax::Unix`], but names must match
+    /// `[_a-zA-Z][_a-zA-Z0-9]*` and `${VAR:=word}` is not an operator. Use
+    /// [`Options::compose`] for the rest of Compose's rules.
+    Compose,
+}
+
+impl Syntax {
+    /// The syntax of the platform the crate was compiled for: [`Syntax::Windows`] on Windows
+    /// and [`Syntax::Unix`] everywhere else.
+    pub fn native() -> Self {
+        if cfg!(windows) {
+            Syntax::Windows
+        } else {
+            Syntax::Unix
+        }
+    }
+
+    pub(crate) fn dollar(&self) -> bool {
+        matches!(self, Syntax::Unix | Syntax::Both | Syntax::Compose)
+    }
+
+    pub(crate) fn percent(&self) -> bool {
+        matches!(self, Syntax::Windows | Syntax::Both)
+    }
+
+    pub(crate) fn paren(&self) -> bool {
+        matches!(self, Syntax::Kubernetes)
+    }
+
+    /// Whether `input` contains a character that could start a placeholder. `false` means
+    /// `input` is all literal text.
+    pub(crate) fn may_match(&self, input: &str) -> bool {
+        match self {
+            Syntax::Custom { open, .. } => !open.is_empty() && input.contains(open.as_str()),
+            Syntax::Kubernetes => input.contains('$'),
+            _ => (self.dollar() && input.contains('$')) || (self.percent() && input.contains('%')),
+        }
+    }
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Syntax::native()
+    }
+}
+
+/// Configuration for an expansion.
+///
+/// The defaults match [`expand_env_vars`].
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub(crate) syntax: Syntax,
+    pub(crate) on_missing: OnMissing,
+    pub(crate) escape: Escape,
+    pub(crate) strict_parse: bool,
+    pub(crate) tilde: bool,
+    pub(crate) depth: usize,
+}
+
+impl Options {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets which placeholder syntax is recognized. Defaults to [`Syntax::native`].
+    pub fn syntax(mut self, syntax: Syntax) -> Self {
+        self.syntax = syntax;
+        self
+    }
+
+    /// Sets how references to unset variables are treated.
+    pub fn on_missing(mut self, on_missing: OnMissing) -> Self {
+        self.on_missing = on_missing;
+        self
+    }
+
+    /// Sets which escape sequences produce a literal sigil.
+    pub fn escape(mut self, escape: Escape) -> Self {
+        self.escape = escape;
+        self
+    }
+
+    /// Sets whether malformed placeholders (unterminated, empty or with an invalid name) are
+    /// errors. By default they are kept as literal text.
+    pub fn strict_parse(mut self, strict_parse: bool) -> Self {
+        self.strict_parse = strict_parse;
+        self
+    }
+
+    /// Sets whether a `~` at the start of a word, or after the `=` of an assignment or a `:` as
+    /// in `PATH`-like values, is expanded: `~` and `~/x` to `HOME` (`USERPROFILE` on Windows),
+    /// `~+` to `PWD`, `~-` to `OLDPWD` and `~user` to the home directory of `user`. Off by
+    /// default.
+    ///
+    /// The variables are looked up in the same source as every other variable. A tilde that
+    /// cannot be resolved is kept as is.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use expand_env_vars::{Options, Syntax};
+    ///
+    /// let vars = HashMap::from([("HOME", "/home/alice")]);
+    /// let options = Options::new().syntax(Syntax::Unix).tilde(true);
+    /// # #[cfg(not(windows))]
+    /// assert_eq!(
+    ///     options.expand_with("PATH=~/bin:/bin:~/.local/bin", &vars).unwrap(),
+    ///     "PATH=/home/alice/bin:/bin:/home/alice/.local/bin"
+    /// );
+    /// ```
+    pub fn tilde(mut self, tilde: bool) -> Self {
+        self.tilde = tilde;
+        self
+    }
+
+    /// Expands references inside the values of variables, such as `$DATA_DIR` in
+    /// `LOG_DIR=$DATA_DIR/logs`, up to `depth` levels deep. Values nested deeper are
+    /// substituted as they are. `0`, the default, substitutes every value as it is.
+    ///
+    /// Errors inside a value are reported at the placeholder in the input that led to it, and
+    /// references that loop back to a variable being expanded fail with
+    /// [`EnvExpansionError::Cycle`].
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use expand_env_vars::{Options, Syntax};
+    ///
+    /// let vars = HashMap::from([("DATA_DIR", "/var/lib/app"), ("LOG_DIR", "$DATA_DIR/logs")]);
+    /// let options = Options::new().syntax(Syntax::Unix).recursive(8);
+    /// assert_eq!(options.expand_with("$LOG_DIR", &vars).unwrap(), "/var/lib/app/logs");
+    /// ```
+    pub fn recursive(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Options that expand like Kubernetes does for `env` values, `command` and `args`:
+    /// [`Syntax::Kubernetes`] with [`OnMissing::Keep`]. [`Options::resolve`] expands a
+    /// container's `env` list in order, as Kubernetes does.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use expand_env_vars::Options;
+    ///
+    /// let vars = HashMap::from([("HOST", "db")]);
+    /// let options = Options::kubernetes();
+    /// assert_eq!(
+    ///     options.expand_with("$(HOST):$(PORT) $$(HOST) $HOST", &vars).unwrap(),
+    ///     "db:$(PORT) $(HOST) $HOST"
+    /// );
+    ///
+    /// let env = [("URL", "$(HOST):$(PORT)"), ("PORT", "5432"), ("DSN", "pg://$(URL)")];
+    /// let resolved = options.resolve_with(env, &vars).unwrap();
+    /// assert_eq!(resolved[0].1, "db:$(PORT)");
+    /// assert_eq!(resolved[2].1, "pg://db:$(PORT)");
+    /// ```
+    pub fn kubernetes() -> Self {
+        Self::new()
+            .syntax(Syntax::Kubernetes)
+            .on_missing(OnMissing::Keep)
+    }
+
+    /// Options that interpolate like Docker Compose does in compose files: [`Syntax::Compose`]
+    /// with `$$` escapes ([`Escape::Double`]), unset variables replaced with empty strings, and
+    /// malformed placeholders such as `${FOO:=bar}` or `${1FOO}` reported as errors
+    /// ([`Options::strict_parse`]).
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use expand_env_vars::Options;
+    ///
+    /// let vars = HashMap::from([("TAG", "v1")]);
+    /// let options = Options::compose();
+    /// assert_eq!(
+    ///     options.expand_with("app:${TAG:-latest} $$HOME ${REGISTRY-docker.io}", &vars).unwrap(),
+    ///     "app:v1 $HOME docker.io"
+    /// );
+    /// assert!(options.expand_with("${TAG:=v2}", &vars).is_err());
+    /// ```
+    pub fn compose() -> Self {
+        Self::new()
+            .syntax(Syntax::Compose)
+            .escape(Escape::Double)
+            .strict_parse(true)
+    }
+
+    /// Shorthand for `on_missing(OnMissing::Error)`.
+    pub fn strict(self) -> Self {
+        self.on_missing(OnMissing::Error)
+    }
+
+    /// Expands environment variable placeholders in `input` using these options.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`EnvExpansionError::MissingVar`] or [`EnvExpansionError::MissingVars`] if a
+    /// referenced variable is unset and [`OnMissing::Error`] or [`OnMissing::Collect`] is in
+    /// effect, [`EnvExpansionError::Required`] for `${VAR:?message}`,
+    /// [`EnvExpansionError::NotUnicode`] if the value of a referenced variable is not valid
+    /// Unicode, [`EnvExpansionError::Cycle`] if values refer to each other in a loop with
+    /// [`Options::recursive`], and [`EnvExpansionError::Parse`] for malformed placeholders if
+    /// [`Options::strict_parse`] is enabled.
+    pub fn expand(&self, input: &str) -> Result<String, EnvExpansionError> {
+        self.expand_with(input, &Env)
+    }
+
+    /// Like [`Options::expand`], but looks variables up in `source` instead of the process
+    /// environment.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand`].
+    pub fn expand_with<S: VarSource + ?Sized>(
+        &self,
+        input: &str,
+        source: &S,
+    ) -> Result<String, EnvExpansionError> {
+        self.expand_full_with(input, source)
+            .map(|expansion| expansion.value)
+    }
+
+    /// Like [`Options::expand`], but borrows `input` instead of copying it when it contains no
+    /// placeholders, or expands to itself.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand`].
+    pub fn expand_cow<'a>(&self, input: &'a str) -> Result<Cow<'a, str>, EnvExpansionError> {
+        self.expand_cow_with(input, &Env)
+    }
+
+    /// Like [`Options::expand_cow`], but looks variables up in `source` instead of the process
+    /// environment.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand`].
+    pub fn expand_cow_with<'a, S: VarSource + ?Sized>(
+        &self,
+        input: &'a str,
+        source: &S,
+    ) -> Result<Cow<'a, str>, EnvExpansionError> {
+        let may_match = self.syntax.may_match(input) || (self.tilde && input.contains('~'));
+        if !may_match {
+            return Ok(Cow::Borrowed(input));
+        }
+        let value = template::expand_full(self, input, &source)?.value;
+        if value == input {
+            return Ok(Cow::Borrowed(input));
+        }
+        Ok(Cow::Owned(value))
+    }
+
+    /// Like [`Options::expand`], but takes and returns OS strings, so neither `input` nor the
+    /// values of variables have to be valid Unicode.
+    ///
+    /// Placeholders are only recognized in the valid Unicode parts of `input`; any other bytes
+    /// are copied through unchanged. Error locations are byte offsets into all of `input`, with
+    /// each invalid sequence shown as a replacement character in the snippet.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand`], except that [`EnvExpansionError::NotUnicode`] is never
+    /// returned.
+    pub fn expand_os(&self, input: &OsStr) -> Result<OsString, EnvExpansionError> {
+        self.expand_os_with(input, &Env)
+    }
+
+    /// Like [`Options::expand_os`], but looks variables up in `source` instead of the process
+    /// environment.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand_os`].
+    pub fn expand_os_with<S: VarSource + ?Sized>(
+        &self,
+        input: &OsStr,
+        source: &S,
+    ) -> Result<OsString, EnvExpansionError> {
+        template::expand_os(self, input, &source)
+    }
+
+    /// Expands placeholders in `path`, replacing a leading `~` with the home directory and
+    /// `~user` with the home directory of `user`.
+    ///
+    /// The path is expanded like [`Options::expand_os`], so nothing is converted through
+    /// `String`. The home directory is read from `HOME` (`USERPROFILE` on Windows), and other
+    /// users' from `/etc/passwd`; a `~` that cannot be resolved is kept as is. Literal parts of
+    /// the path are kept as they are; only separators next to an expanded value collapse, so a
+    /// value that starts with a separator is appended to the path so far rather than replacing
+    /// it, and an empty value leaves no doubled separator behind. An empty first component
+    /// keeps an absolute path absolute.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use std::path::Path;
+    /// use expand_env_vars::{Options, Syntax};
+    ///
+    /// let vars = HashMap::from([("HOME", "/home/alice"), ("APP", "demo")]);
+    /// let options = Options::new().syntax(Syntax::Unix);
+    /// # #[cfg(unix)]
+    /// assert_eq!(
+    ///     options.expand_path_with(Path::new("~/cache/${APP}/data"), &vars).unwrap(),
+    ///     Path::new("/home/alice/cache/demo/data"),
+    /// );
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand_os`].
+    pub fn expand_path(&self, path: &Path) -> Result<PathBuf, EnvExpansionError> {
+        self.expand_path_with(path, &Env)
+    }
+
+    /// Like [`Options::expand_path`], but looks variables, including the home directory, up
+    /// in `source` instead of the process environment.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand_os`].
+    pub fn expand_path_with<S: VarSource + ?Sized>(
+        &self,
+        path: &Path,
+        source: &S,
+    ) -> Result<PathBuf, EnvExpansionError> {
+        path::expand(self, path, &source)
+    }
+
+    /// Expands a set of variables whose values may refer to each other, such as the entries of
+    /// an env file, regardless of the order they are listed in.
+    ///
+    /// Each value is expanded after the values it refers to; names that are not in `vars` are
+    /// looked up in the process environment, as is a variable's own name, so
+    /// `PATH=/opt/bin:$PATH` extends the outer `PATH`. Values that are already expanded are not
+    /// expanded again, even with [`Options::recursive`]. If a name appears more than once, the
+    /// last value wins. The result lists every variable once, in the order of first appearance.
+    ///
+    /// With [`Syntax::Kubernetes`], the values are expanded in order like the `env` list of a
+    /// container instead: each value only sees the entries before it, so a reference to a later
+    /// entry is unset. With [`OnMissing::Keep`], references to unset names are kept as written.
+    ///
+    /// ```
+    /// use expand_env_vars::{Options, Syntax};
+    ///
+    /// let vars = [("BIN", "$BASE/bin"), ("BASE", "/opt")];
+    /// let resolved = Options::new().syntax(Syntax::Unix).resolve(vars).unwrap();
+    /// assert_eq!(resolved[0], ("BIN".to_string(), "/opt/bin".to_string()));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns [`EnvExpansionError::Unresolvable`] listing every cycle among the variables
+    /// and every reference to a name that is set nowhere, unless [`OnMissing::Keep`] is in
+    /// effect; [`OnMissing::Empty`] and [`OnMissing::Error`] are treated like
+    /// [`OnMissing::Collect`]. Variables that depend on a cycle are not expanded, so their own
+    /// problems are not reported. Otherwise same as [`Options::expand`].
+    pub fn resolve<K, V>(
+        &self,
+        vars: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Vec<(String, String)>, EnvExpansionError>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.resolve_with(vars, &Env)
+    }
+
+    /// Like [`Options::resolve`], but looks names that are not in `vars` up in `source`
+    /// instead of the process environment.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::resolve`].
+    pub fn resolve_with<K, V, S>(
+        &self,
+        vars: impl IntoIterator<Item = (K, V)>,
+        source: &S,
+    ) -> Result<Vec<(String, String)>, EnvExpansionError>
+    where
+        K: Into<String>,
+        V: Into<String>,
+        S: VarSource + ?Sized,
+    {
+        let vars = vars
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()));
+        resolve::resolve(self, vars.collect(), &source)
+    }
+
+    /// Like [`Options::expand`], but also returns the variables assigned with
+    /// `${VAR:=default}` or `${VAR=default}`.
+    ///
+    /// Assignments are only visible to later references in the same input; the process
+    /// environment is never modified. Writing them back is left to the caller:
+    ///
+    /// ```
+    /// use expand_env_vars::{Options, Syntax};
+    ///
+    /// let options = Options::new().syntax(Syntax::Unix);
+    /// let expansion = options.expand_full("${DOCS_ASSIGN_PORT:=8080}").unwrap();
+    /// assert_eq!(expansion.value, "8080");
+    /// for (name, value) in &expansion.assignments {
+    ///     // SAFETY: no other threads read or write the environment here.
+    ///     unsafe { std::env::set_var(name, value) };
+    /// }
+    /// assert_eq!(std::env::var("DOCS_ASSIGN_PORT").unwrap(), "8080");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand`].
+    pub fn expand_full(&self, input: &str) -> Result<Expansion, EnvExpansionError> {
+        self.expand_full_with(input, &Env)
+    }
+
+    /// Like [`Options::expand_full`], but looks variables up in `source` instead of the
+    /// process environment.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand`].
+    pub fn expand_full_with<S: VarSource + ?Sized>(
+        &self,
+        input: &str,
+        source: &S,
+    ) -> Result<Expansion, EnvExpansionError> {
+        template::expand_full(self, input, &source)
+    }
+
+    /// Parses `input` into a [`Template`] that can be rendered repeatedly with these options.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`EnvExpansionError::Parse`] for malformed placeholders if
+    /// [`Options::strict_parse`] is enabled.
+    pub fn parse(&self, input: &str) -> Result<Template, EnvExpansionError> {
+        Template::with_options(input, self)
+    }
+}
+
+/// The result of [`Options::expand_full`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Expansion {
+    /// The expanded string.
+    pub value: String,
+    /// Variables assigned with `${VAR:=default}` or `${VAR=default}`, in the order they were
+    /// first assigned, with their final values.
+    pub assignments: Vec<(String, String)>,
+}
+
 /// Expands environment variable placeholders in a string with actual environment values.
 ///
-/// - On **Unix**, supports `$VAR` and `${VAR}`.
+/// The syntax is chosen by [`Syntax::native`]:
+///
+/// - On **Unix**, supports `$VAR` and `${VAR}`, plus the POSIX default forms
+///   `${VAR:-default}` and `${VAR-default}`, the error forms `${VAR:?message}` and
+///   `${VAR?message}`, the alternate-value forms `${VAR:+alt}` and `${VAR+alt}`, and the
+///   assign-default forms `${VAR:=default}` and `${VAR=default}`. Assignments only affect the
+///   rest of `input`; use [`Options::expand_full`] to retrieve them.
 /// - On **Windows**, supports `%VAR%`.
 ///
 /// # Errors
 ///
-/// Currently, missing variables are replaced with an empty string.
-/// A stricter mode can be implemented later to return an error for missing variables.
+/// Missing variables are replaced with an empty string. Use [`expand_env_vars_strict`] to
+/// return an error for missing variables.
+///
+/// Returns [`EnvExpansionError::Required`] if a variable marked with `${VAR:?message}` is not
+/// set, and [`EnvExpansionError::NotUnicode`] if the value of a referenced variable is not
+/// valid Unicode; use [`expand_env_vars_os`] to accept such values.
 ///
 pub fn expand_env_vars(input: &str) -> Result<String, EnvExpansionError> {
-    #[cfg(unix)]
-    {
-        let mut result = String::with_capacity(input.len());
-        let chars: Vec<char> = input.chars().collect();
-        let mut i = 0;
-
-        while i < chars.len() {
-            if chars[i] == '$' {
-                if i + 1 < chars.len() && chars[i + 1] == '{' {
-                    // Handle ${VAR}
-                    let mut j = i + 2;
-                    while j < chars.len() && chars[j] != '}' {
-                        j += 1;
-                    }
+    Options::new().expand(input)
+}
 
-                    if j < chars.len() {
-                        let var_name: String = chars[i + 2..j].iter().collect();
-                        let val = env::var(&var_name).unwrap_or_default();
-                        result.push_str(&val);
-                        i = j + 1;
-                    } else {
-                        // No closing brace, treat as literal
-                        result.push('$');
-                        i += 1;
-                    }
-                } else {
-                    // Handle $VAR
-                    let mut j = i + 1;
-                    while j < chars.len() && (chars[j].is_ascii_alphanumeric() || chars[j] == '_') {
-                        j += 1;
-                    }
-                    let var_name: String = chars[i + 1..j].iter().collect();
-                    let val = env::var(&var_name).unwrap_or_default();
-                    result.push_str(&val);
-                    i = j;
-                }
-            } else {
-                result.push(chars[i]);
-                i += 1;
-            }
-        }
+/// Like [`expand_env_vars`], but fails on the first unset variable.
+///
+/// # Errors
+///
+/// Returns [`EnvExpansionError::MissingVar`] with the name of the first unset variable.
+/// Otherwise same as [`expand_env_vars`].
+pub fn expand_env_vars_strict(input: &str) -> Result<String, EnvExpansionError> {
+    Options::new().strict().expand(input)
+}
 
-        Ok(result)
-    }
+/// Like [`expand_env_vars`], but borrows `input` instead of copying it when it contains no
+/// placeholders.
+///
+/// ```
+/// use std::borrow::Cow;
+///
+/// let line = "no placeholders here";
+/// assert!(matches!(expand_env_vars::expand_env_vars_cow(line), Ok(Cow::Borrowed(_))));
+/// ```
+///
+/// # Errors
+///
+/// Same as [`expand_env_vars`].
+pub fn expand_env_vars_cow(input: &str) -> Result<Cow<'_, str>, EnvExpansionError> {
+    Options::new().expand_cow(input)
+}
 
-    #[cfg(windows)]
-    {
-        let mut result = String::with_capacity(input.len());
-        let chars: Vec<char> = input.chars().collect();
-        let mut i = 0;
-
-        while i < chars.len() {
-            if chars[i] == '%' {
-                let mut j = i + 1;
-                while j < chars.len() && chars[j] != '%' {
-                    j += 1;
-                }
+/// Like [`expand_env_vars`], but takes and returns OS strings, so variables whose values are
+/// not valid Unicode are expanded instead of failing. See [`Options::expand_os`].
+///
+/// # Errors
+///
+/// Returns [`EnvExpansionError::Required`] if a variable marked with `${VAR:?message}` is not
+/// set.
+pub fn expand_env_vars_os(input: &OsStr) -> Result<OsString, EnvExpansionError> {
+    Options::new().expand_os(input)
+}
 
-                if j < chars.len() {
-                    let var_name: String = chars[i + 1..j].iter().collect();
-                    let val = env::var(&var_name).unwrap_or_default();
-                    result.push_str(&val);
-                    i = j + 1;
-                } else {
-                    // No closing %, treat as literal
-                    result.push('%');
-                    i += 1;
-                }
-            } else {
-                result.push(chars[i]);
-                i += 1;
-            }
-        }
+/// Expands placeholders in `path` and a leading `~` or `~user`. See
+/// [`Options::expand_path`].
+///
+/// # Errors
+///
+/// Same as [`expand_env_vars_os`].
+pub fn expand_path(path: &Path) -> Result<PathBuf, EnvExpansionError> {
+    Options::new().expand_path(path)
+}
 
-        Ok(result)
-    }
+/// Expands a set of variables whose values may refer to each other, in dependency order. See
+/// [`Options::resolve`].
+///
+/// # Errors
+///
+/// Same as [`Options::resolve`].
+pub fn resolve_vars<K, V>(
+    vars: impl IntoIterator<Item = (K, V)>,
+) -> Result<Vec<(String, String)>, EnvExpansionError>
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    Options::new().resolve(vars)
+}
+
+/// Returns every variable referenced by `input`, in order of appearance, without expanding
+/// anything.
+///
+/// References inside operator words, such as `$B` in `${A:-$B}`, are included after the
+/// reference they belong to. Use [`Template::names`] for a deduplicated list of names, and
+/// [`Options::parse`] to choose the syntax.
+///
+/// ```
+/// # #[cfg(unix)] {
+/// let refs = expand_env_vars::referenced_vars("${HOST:-localhost}:$PORT");
+/// assert_eq!(refs[0].name, "HOST");
+/// assert!(refs[0].has_default());
+/// assert_eq!(refs[1].name, "PORT");
+/// assert!(!refs[1].has_default());
+/// # }
+/// ```
+pub fn referenced_vars(input: &str) -> Vec<Reference> {
+    Template::parse(input).references()
+}
+
+/// Like [`expand_env_vars`], but looks variables up in `source` instead of the process
+/// environment.
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let vars = HashMap::from([("NAME", "alice")]);
+/// # #[cfg(unix)]
+/// assert_eq!(expand_env_vars::expand_with("Hello $NAME!", &vars).unwrap(), "Hello alice!");
+/// ```
+///
+/// # Errors
+///
+/// Same as [`expand_env_vars`].
+pub fn expand_with<S: VarSource + ?Sized>(
+    input: &str,
+    source: &S,
+) -> Result<String, EnvExpansionError> {
+    Options::new().expand_with(input, source)
 }
 
 #[cfg(feature = "regex")]
 pub mod regex {
     use regex::Regex;
 
-    use std::{env, sync::LazyLock};
+    use std::borrow::Cow;
+    use std::sync::LazyLock;
 
-    use super::EnvExpansionError;
-    use std::fmt;
+    use super::{Env, EnvExpansionError, Location, Span, VarSource};
 
-    static UNIX_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$(\w+)|\$\{(\w+)\}").unwrap());
-    static WINDOWS_RE: LazyLock<Regex> = LazyLock::new(||Regex::new(r"%(\w+)%").unwrap());
+    #[cfg(unix)]
+    static UNIX_RE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"\$(\w+)|\$\{(\w+)(?:(?<op>:?[-?+])(?<word>[^}]*))?\}").unwrap()
+    });
+    #[cfg(windows)]
+    static WINDOWS_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"%(\w+)%").unwrap());
 
     /// Expands environment variable placeholders in a string with actual environment values with
     /// regex.
     ///
-    /// - On **Unix**, supports `$VAR` and `${VAR}`.
+    /// - On **Unix**, supports `$VAR` and `${VAR}`, plus `${VAR:-default}`, `${VAR:?message}`
+    ///   and `${VAR:+alternate}` with or without the colon. Unlike [`crate::expand_env_vars`],
+    ///   the word after the operator cannot contain nested `${...}` expressions.
     /// - On **Windows**, supports `%VAR%`.
     ///
     /// # Errors
     ///
-    /// Currently, missing variables are replaced with an empty string.
-    /// A stricter mode can be implemented later to return an error for missing variables.
+    /// Missing variables are replaced with an empty string. Use [`expand_env_vars_strict`] to
+    /// return an error for missing variables.
+    ///
+    /// Returns [`EnvExpansionError::Required`] if a variable marked with `${VAR:?message}` is
+    /// not set, and [`EnvExpansionError::NotUnicode`] if the value of a referenced variable is
+    /// not valid Unicode.
     ///
     pub fn expand_env_vars(input: &str) -> Result<String, EnvExpansionError> {
-        #[cfg(unix)]
-        {
-            let result = UNIX_RE.replace_all(input, |caps: &regex::Captures| {
-                let var_name = caps
-                    .get(1)
-                    .or_else(|| caps.get(2))
-                    .map(|m| m.as_str())
-                    .unwrap_or("");
-                env::var(var_name).unwrap_or_default()
-            });
-            Ok(result.into_owned())
+        expand(input, 0, input.len(), &Env, false)
+    }
+
+    /// Like [`expand_env_vars`], but fails on the first unset variable.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`EnvExpansionError::MissingVar`] with the name of the first unset variable.
+    /// Otherwise same as [`expand_env_vars`].
+    pub fn expand_env_vars_strict(input: &str) -> Result<String, EnvExpansionError> {
+        expand(input, 0, input.len(), &Env, true)
+    }
+
+    /// Like [`expand_env_vars`], but looks variables up in `source` instead of the process
+    /// environment.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`expand_env_vars`].
+    pub fn expand_with<S: VarSource + ?Sized>(
+        input: &str,
+        source: &S,
+    ) -> Result<String, EnvExpansionError> {
+        expand(input, 0, input.len(), &source, false)
+    }
+
+    /// Like [`expand_env_vars`], but borrows `input` instead of copying it when it contains no
+    /// placeholders.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`expand_env_vars`].
+    pub fn expand_env_vars_cow(input: &str) -> Result<Cow<'_, str>, EnvExpansionError> {
+        if !regex().is_match(input) {
+            return Ok(Cow::Borrowed(input));
         }
+        expand(input, 0, input.len(), &Env, false).map(Cow::Owned)
+    }
 
+    fn regex() -> &'static Regex {
+        #[cfg(unix)]
+        return &UNIX_RE;
         #[cfg(windows)]
-        {
-            let result = WINDOWS_RE.replace_all(input, |caps: &regex::Captures| {
-                let var_name = caps.get(1).map(|m| m.as_str()).unwrap_or("");
-                env::var(var_name).unwrap_or_default()
-            });
-            result.into_owned()
+        return &WINDOWS_RE;
+    }
+
+    /// Expands `input[start..end]`; `input` is kept whole to locate errors.
+    fn expand(
+        input: &str,
+        start: usize,
+        end: usize,
+        source: &dyn VarSource,
+        strict: bool,
+    ) -> Result<String, EnvExpansionError> {
+        let re = regex();
+        let haystack = &input[start..end];
+        let mut result = String::with_capacity(haystack.len());
+        let mut last = 0;
+        for caps in re.captures_iter(haystack) {
+            let whole = caps.get(0).unwrap();
+            let var_name = caps
+                .iter()
+                .skip(1)
+                .flatten()
+                .next()
+                .map(|m| m.as_str())
+                .unwrap_or("");
+            let location = || {
+                let span = Span {
+                    start: start + whole.start(),
+                    end: start + whole.end(),
+                };
+                Location::new(input, span)
+            };
+            result.push_str(&haystack[last..whole.start()]);
+
+            let val = source.var(var_name);
+            if val.is_none()
+                && let Some(value) = source.var_os(var_name)
+            {
+                return Err(EnvExpansionError::NotUnicode {
+                    name: var_name.to_string(),
+                    value: value.into_owned(),
+                    location: location(),
+                });
+            }
+            let Some(op) = caps.name("op").map(|m| m.as_str()) else {
+                match val {
+                    Some(val) => result.push_str(&val),
+                    None if strict => {
+                        return Err(EnvExpansionError::MissingVar {
+                            name: var_name.to_string(),
+                            location: location(),
+                        });
+                    }
+                    None => {}
+                }
+                last = whole.end();
+                continue;
+            };
+
+            let word = caps.name("word").unwrap();
+            let (word_start, word_end) = (start + word.start(), start + word.end());
+            let set = val
+                .as_deref()
+                .is_some_and(|val| !(op.starts_with(':') && val.is_empty()));
+            match (op.as_bytes()[op.len() - 1], set) {
+                (b'-' | b'?', true) => result.push_str(val.as_deref().unwrap_or_default()),
+                (b'-', false) | (b'+', true) => {
+                    result.push_str(&expand(input, word_start, word_end, source, strict)?);
+                }
+                (b'?', false) => {
+                    return Err(EnvExpansionError::Required {
+                        name: var_name.to_string(),
+                        message: expand(input, word_start, word_end, source, strict)?,
+                        location: location(),
+                    });
+                }
+                _ => {}
+            }
+            last = whole.end();
         }
+        result.push_str(&haystack[last..]);
+        Ok(result)
     }
 }
 
+/// Serializes the tests that read or modify the process environment.
+#[cfg(test)]
+pub(crate) fn env_lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    LOCK.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+
+    fn unix() -> Options {
+        Options::new().syntax(Syntax::Unix)
+    }
 
     #[test]
     fn test_single_var_unix() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("USER", "alice");
         }
@@ -176,6 +1061,7 @@ mod tests {
 
     #[test]
     fn test_braced_var_unix() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("HOME", "/home/alice");
         }
@@ -186,6 +1072,7 @@ mod tests {
 
     #[test]
     fn test_multiple_vars_unix() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("USER", "bob");
             std::env::set_var("SHELL", "/bin/bash");
@@ -197,6 +1084,7 @@ mod tests {
 
     #[test]
     fn test_missing_var_unix() {
+        let _env = env_lock();
         unsafe {
             std::env::remove_var("DOES_NOT_EXIST");
         }
@@ -205,9 +1093,465 @@ mod tests {
         assert_eq!(output, "This is ");
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_strict_missing_var_unix() {
+        let _env = env_lock();
+        unsafe {
+            std::env::remove_var("STRICT_DOES_NOT_EXIST");
+        }
+        let input = "Path: ${STRICT_DOES_NOT_EXIST}/bin";
+        let err = expand_env_vars_strict(input).unwrap_err();
+        assert!(
+            matches!(err, EnvExpansionError::MissingVar { ref name, .. } if name == "STRICT_DOES_NOT_EXIST")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_strict_set_var_unix() {
+        let _env = env_lock();
+        unsafe {
+            std::env::set_var("STRICT_APP_DIR", "/opt/app");
+        }
+        let input = "$STRICT_APP_DIR/bin";
+        let output = expand_env_vars_strict(input).unwrap();
+        assert_eq!(output, "/opt/app/bin");
+    }
+
+    #[test]
+    fn test_collect_missing_vars_unix() {
+        let vars = HashMap::from([("COLLECT_PRESENT", "ok")]);
+        let input = "$COLLECT_MISSING_A/$COLLECT_PRESENT/${COLLECT_MISSING_B}";
+        let err = unix()
+            .on_missing(OnMissing::Collect)
+            .expand_with(input, &vars)
+            .unwrap_err();
+        let EnvExpansionError::MissingVars(missing) = err else {
+            panic!("expected MissingVars, got {err:?}");
+        };
+        assert_eq!(
+            missing,
+            vec![
+                Missing {
+                    name: "COLLECT_MISSING_A".to_string(),
+                    location: Location::new(input, Span { start: 0, end: 18 }),
+                },
+                Missing {
+                    name: "COLLECT_MISSING_B".to_string(),
+                    location: Location::new(input, Span { start: 36, end: 56 }),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_location() {
+        let vars = HashMap::from([("SET", "1")]);
+        let input = "a: $SET\nb: ${SET}/${UNSET}\n";
+        let err = Options::new()
+            .syntax(Syntax::Unix)
+            .strict()
+            .expand_with(input, &vars)
+            .unwrap_err();
+        let location = err.location().unwrap();
+        assert_eq!(location.offset(), 18);
+        assert_eq!((location.line, location.column), (2, 11));
+        assert_eq!(location.snippet, "b: ${SET}/${UNSET}");
+        assert_eq!(
+            err.render(),
+            "error: Missing environment variable: UNSET\n \
+             --> 2:11\n  \
+               |\n\
+             2 | b: ${SET}/${UNSET}\n  \
+               |           ^^^^^^^^"
+        );
+
+        let err = Options::new()
+            .syntax(Syntax::Unix)
+            .on_missing(OnMissing::Collect)
+            .expand_with("$A $B", &vars)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Missing environment variables: A (line 1, column 1), B (line 1, column 4)"
+        );
+        assert_eq!(err.render().matches("^^").count(), 2);
+    }
+
+    #[test]
+    fn test_strict_parse_expand() {
+        let vars = HashMap::from([("HOME", "/home/alice")]);
+        let input = "PATH=${HOME/bin";
+        let lenient = Options::new().syntax(Syntax::Unix);
+        assert_eq!(lenient.expand_with(input, &vars).unwrap(), input);
+        let err = lenient
+            .strict_parse(true)
+            .expand_with(input, &vars)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Unterminated placeholder");
+        assert_eq!(err.location().unwrap().column, 6);
+    }
+
+    #[test]
+    fn test_default_value_unix() {
+        let vars = HashMap::from([("DEFAULT_EMPTY", ""), ("DEFAULT_SET", "9090")]);
+        let expand = |input: &str| unix().expand_with(input, &vars).unwrap();
+        let input = "${DEFAULT_UNSET:-8080} ${DEFAULT_UNSET-8080}";
+        assert_eq!(expand(input), "8080 8080");
+        let input = "[${DEFAULT_EMPTY:-8080}] [${DEFAULT_EMPTY-8080}]";
+        assert_eq!(expand(input), "[8080] []");
+        let input = "${DEFAULT_SET:-8080} ${DEFAULT_SET-8080}";
+        assert_eq!(expand(input), "9090 9090");
+    }
+
+    #[test]
+    fn test_nested_default_value_unix() {
+        let vars = HashMap::from([("NESTED_HOST", "db.local")]);
+        let input = "${NESTED_UNSET:-$NESTED_HOST:${NESTED_UNSET:-5432}}";
+        assert_eq!(unix().expand_with(input, &vars).unwrap(), "db.local:5432");
+    }
+
+    #[test]
+    fn test_strict_default_value_unix() {
+        let vars: HashMap<&str, &str> = HashMap::new();
+        let strict = unix().strict();
+        let input = "${STRICT_DEFAULT_UNSET:-fallback}";
+        assert_eq!(strict.expand_with(input, &vars).unwrap(), "fallback");
+        let input = "${STRICT_DEFAULT_UNSET:-$STRICT_DEFAULT_UNSET}";
+        assert!(strict.expand_with(input, &vars).is_err());
+    }
+
+    #[test]
+    fn test_required_var_unix() {
+        let vars = HashMap::from([("REQUIRED_EMPTY", ""), ("REQUIRED_SET", "value")]);
+        let expand = |input: &str| unix().expand_with(input, &vars);
+        let err = expand("${REQUIRED_UNSET:?must be set}").unwrap_err();
+        assert!(matches!(
+            err,
+            EnvExpansionError::Required { ref name, ref message, .. }
+                if name == "REQUIRED_UNSET" && message == "must be set"
+        ));
+        assert_eq!(err.to_string(), "REQUIRED_UNSET: must be set");
+
+        assert!(expand("${REQUIRED_EMPTY:?}").is_err());
+        assert_eq!(expand("[${REQUIRED_EMPTY?}]").unwrap(), "[]");
+        assert_eq!(expand("${REQUIRED_SET:?oops}").unwrap(), "value");
+    }
+
+    #[test]
+    fn test_alternate_value_unix() {
+        let vars = HashMap::from([("ALT_DEBUG", "1"), ("ALT_EMPTY", "")]);
+        let expand = |input: &str| unix().expand_with(input, &vars).unwrap();
+        let input = "run ${ALT_DEBUG:+--verbose=$ALT_DEBUG}";
+        assert_eq!(expand(input), "run --verbose=1");
+        let input = "[${ALT_EMPTY:+x}] [${ALT_EMPTY+x}]";
+        assert_eq!(expand(input), "[] [x]");
+        let input = "[${ALT_UNSET:+x}] [${ALT_UNSET+x}]";
+        assert_eq!(expand(input), "[] []");
+    }
+
+    #[test]
+    fn test_assign_default_unix() {
+        let vars = HashMap::from([("ASSIGN_EMPTY", ""), ("ASSIGN_SET", "set")]);
+        let input = "${ASSIGN_UNSET:=a} $ASSIGN_UNSET [${ASSIGN_EMPTY=b}] ${ASSIGN_SET:=c}";
+        let expansion = unix().expand_full_with(input, &vars).unwrap();
+        assert_eq!(expansion.value, "a a [] set");
+        assert_eq!(
+            expansion.assignments,
+            vec![("ASSIGN_UNSET".to_string(), "a".to_string())]
+        );
+        assert!(!vars.contains_key("ASSIGN_UNSET"));
+
+        let input = "${ASSIGN_UNSET=} ${ASSIGN_UNSET:=x}${ASSIGN_UNSET:=y}";
+        let expansion = unix().expand_full_with(input, &vars).unwrap();
+        assert_eq!(expansion.value, " xx");
+        assert_eq!(
+            expansion.assignments,
+            vec![("ASSIGN_UNSET".to_string(), "x".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_escape_unix() {
+        let vars = HashMap::from([("ESCAPE_PRICE", "5")]);
+        let input = r"$$ESCAPE_PRICE \$ESCAPE_PRICE $ESCAPE_PRICE";
+        let double = unix().escape(Escape::Double);
+        let expand = |options: &Options, input: &str| options.expand_with(input, &vars).unwrap();
+        assert_eq!(expand(&double, input), r"$ESCAPE_PRICE \5 5");
+        let backslash = unix().escape(Escape::Backslash);
+        assert_eq!(expand(&backslash, input), "$5 $ESCAPE_PRICE 5");
+        let both = unix().escape(Escape::Both);
+        assert_eq!(expand(&both, input), "$ESCAPE_PRICE $ESCAPE_PRICE 5");
+
+        let input = "${ESCAPE_UNSET:-$${ESCAPE_PRICE}}";
+        assert_eq!(expand(&double, input), "${ESCAPE_PRICE}");
+    }
+
+    #[test]
+    fn test_bare_dollar_unix() {
+        let input = "costs $ 5, $. or $";
+        assert_eq!(expand_env_vars_strict(input).unwrap(), input);
+    }
+
+    #[test]
+    fn test_expand_with_map() {
+        let vars = HashMap::from([("USER", "dora"), ("HOME", "/home/dora")]);
+        let unix = Options::new().syntax(Syntax::Unix);
+        let input = "$USER lives in ${HOME} [${SHELL:-sh}]";
+        assert_eq!(
+            unix.expand_with(input, &vars).unwrap(),
+            "dora lives in /home/dora [sh]"
+        );
+        let windows = Options::new().syntax(Syntax::Windows);
+        assert_eq!(windows.expand_with("%USER%", &vars).unwrap(), "dora");
+        assert!(unix.strict().expand_with("$SHELL", &vars).is_err());
+    }
+
+    #[test]
+    fn test_expand_cow() {
+        let vars = HashMap::from([("USER", "dora")]);
+        let unix = Options::new().syntax(Syntax::Unix);
+        for input in ["plain text", "100% sure", "costs $ 5", ""] {
+            let output = unix.expand_cow_with(input, &vars).unwrap();
+            assert!(matches!(output, Cow::Borrowed(s) if s == input));
+        }
+        let output = unix.expand_cow_with("hi $USER", &vars).unwrap();
+        assert!(matches!(output, Cow::Owned(ref s) if s == "hi dora"));
+        assert!(matches!(
+            unix.clone()
+                .escape(Escape::Backslash)
+                .expand_cow_with(r"\$USER", &vars)
+                .unwrap(),
+            Cow::Owned(_)
+        ));
+        let windows = Options::new().syntax(Syntax::Windows);
+        assert!(matches!(
+            windows.expand_cow_with("$USER", &vars).unwrap(),
+            Cow::Borrowed(_)
+        ));
+        assert!(unix.strict().expand_cow_with("$UNSET", &vars).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_not_unicode_unix() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let _env = env_lock();
+        unsafe {
+            std::env::set_var("NOT_UNICODE_DIR", OsStr::from_bytes(b"caf\xe9"));
+        }
+        let err = expand_env_vars("cd $NOT_UNICODE_DIR").unwrap_err();
+        assert!(
+            matches!(err, EnvExpansionError::NotUnicode { ref name, .. } if name == "NOT_UNICODE_DIR")
+        );
+        assert_eq!(
+            err.to_string(),
+            "Environment variable is not valid Unicode: NOT_UNICODE_DIR"
+        );
+
+        let output = expand_env_vars_os(OsStr::new("cd $NOT_UNICODE_DIR")).unwrap();
+        assert_eq!(output.as_bytes(), b"cd caf\xe9");
+        let input = OsStr::from_bytes(b"\xff$NOT_UNICODE_DIR/\xfe${NOT_UNICODE_DIR}");
+        let output = expand_env_vars_os(input).unwrap();
+        assert_eq!(output.as_bytes(), b"\xffcaf\xe9/\xfecaf\xe9");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_os_shares_state_across_invalid_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let vars: HashMap<&str, &str> = HashMap::new();
+        let input = OsStr::from_bytes(b"${A:=x}\xff$A");
+        let output = unix().expand_os_with(input, &vars).unwrap();
+        assert_eq!(output.as_bytes(), b"x\xffx");
+
+        let input = OsStr::from_bytes(b"$M1\xff\xfe\n $M2");
+        let collect = unix().on_missing(OnMissing::Collect);
+        let Err(EnvExpansionError::MissingVars(missing)) = collect.expand_os_with(input, &vars)
+        else {
+            panic!("expected MissingVars");
+        };
+        let found: Vec<_> = missing
+            .iter()
+            .map(|m| (m.name.as_str(), m.location.offset(), m.location.line))
+            .collect();
+        assert_eq!(found, [("M1", 0, 1), ("M2", 7, 2)]);
+
+        let input = OsStr::from_bytes(b"a\xff${B");
+        let Err(EnvExpansionError::Parse { location, .. }) =
+            unix().strict_parse(true).expand_os_with(input, &vars)
+        else {
+            panic!("expected Parse");
+        };
+        assert_eq!((location.offset(), location.column), (2, 3));
+        assert_eq!(location.snippet, "a\u{fffd}${B");
+    }
+
+    #[test]
+    fn test_options_on_missing() {
+        let vars: HashMap<&str, &str> = HashMap::new();
+        let input = "[$OPTIONS_DOES_NOT_EXIST]";
+        let lenient = unix().on_missing(OnMissing::Empty);
+        assert_eq!(lenient.expand_with(input, &vars).unwrap(), "[]");
+        let strict = unix().on_missing(OnMissing::Error);
+        assert!(strict.expand_with(input, &vars).is_err());
+    }
+
+    #[test]
+    fn test_windows_syntax() {
+        let vars = HashMap::from([("SYNTAX_WIN_USER", "charlie")]);
+        let windows = Options::new().syntax(Syntax::Windows);
+        let input = "%SYNTAX_WIN_USER% [%SYNTAX_WIN_UNSET%] $SYNTAX_WIN_USER 100%";
+        assert_eq!(
+            windows.expand_with(input, &vars).unwrap(),
+            "charlie [] $SYNTAX_WIN_USER 100%"
+        );
+        let escaped = windows.escape(Escape::Double);
+        assert_eq!(
+            escaped
+                .expand_with("100%% %SYNTAX_WIN_USER%", &vars)
+                .unwrap(),
+            "100% charlie"
+        );
+    }
+
+    #[test]
+    fn test_empty_names_are_literal() {
+        let vars = HashMap::from([("X", "x")]);
+        let strict = |syntax: Syntax| Options::new().syntax(syntax).strict();
+        for (syntax, input) in [
+            (Syntax::Windows, "100%% sure, %:~1% %X%"),
+            (Syntax::Unix, "${} ${:-d} $X"),
+            (Syntax::Kubernetes, "$() $(X)"),
+        ] {
+            let expected = input
+                .replace("%X%", "x")
+                .replace("$(X)", "x")
+                .replace("$X", "x");
+            assert_eq!(
+                strict(syntax.clone()).expand_with(input, &vars).unwrap(),
+                expected
+            );
+            assert_eq!(strict(syntax).parse(input).unwrap().names(), ["X"]);
+        }
+        let err = strict(Syntax::Windows)
+            .strict_parse(true)
+            .expand_with("100%% sure", &vars)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            EnvExpansionError::Parse {
+                kind: ParseErrorKind::EmptyName,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_both_syntax() {
+        let vars = HashMap::from([("SYNTAX_BOTH_DIR", "data")]);
+        let both = Options::new().syntax(Syntax::Both);
+        let input = "$SYNTAX_BOTH_DIR/${SYNTAX_BOTH_DIR}\\%SYNTAX_BOTH_DIR%";
+        assert_eq!(both.expand_with(input, &vars).unwrap(), "data/data\\data");
+    }
+
+    #[test]
+    fn test_custom_syntax() {
+        let vars = HashMap::from([("SYNTAX_CUSTOM_NAME", "world")]);
+        let custom = Options::new().syntax(Syntax::Custom {
+            open: "{{".to_string(),
+            close: "}}".to_string(),
+        });
+        let input = "hello {{SYNTAX_CUSTOM_NAME}} $SYNTAX_CUSTOM_NAME {{unclosed";
+        assert_eq!(
+            custom.expand_with(input, &vars).unwrap(),
+            "hello world $SYNTAX_CUSTOM_NAME {{unclosed"
+        );
+    }
+
+    /// Cases from the Compose interpolation documentation and compose-go's template tests.
+    #[test]
+    fn test_compose_conformance() {
+        let vars = HashMap::from([("FOO", "foo"), ("EMPTY", "")]);
+        let compose = Options::compose();
+        let expand = |input: &str| compose.expand_with(input, &vars);
+
+        for (input, expected) in [
+            ("${FOO}", "foo"),
+            ("$FOO", "foo"),
+            ("${FOO}bar", "foobar"),
+            ("$FOO-bar", "foo-bar"),
+            ("${UNSET}", ""),
+            ("$UNSET", ""),
+            // ${VAR:-default} and ${VAR-default}
+            ("${FOO:-default}", "foo"),
+            ("${UNSET:-default}", "default"),
+            ("${EMPTY:-default}", "default"),
+            ("${UNSET-default}", "default"),
+            ("${EMPTY-default}", ""),
+            ("${UNSET:-a b c}", "a b c"),
+            // ${VAR:+replacement} and ${VAR+replacement}
+            ("${FOO:+replacement}", "replacement"),
+            ("${EMPTY:+replacement}", ""),
+            ("${EMPTY+replacement}", "replacement"),
+            ("${UNSET+replacement}", ""),
+            // ${VAR:?err} and ${VAR?err} when set
+            ("${FOO:?err}", "foo"),
+            ("${EMPTY?err}", ""),
+            // Nested interpolation
+            ("${UNSET:-${FOO}}", "foo"),
+            ("${UNSET:-$FOO}", "foo"),
+            ("${UNSET:-${UNSET2:-default}}", "default"),
+            ("${FOO?$UNSET}", "foo"),
+            // $$ escapes
+            (
+                "$$VAR_NOT_INTERPOLATED_BY_COMPOSE",
+                "$VAR_NOT_INTERPOLATED_BY_COMPOSE",
+            ),
+            ("$${FOO}", "${FOO}"),
+            ("$$$FOO", "$foo"),
+            ("${UNSET:-$$FOO}", "$FOO"),
+            // A $ that does not start a name is kept
+            ("$1", "$1"),
+            ("costs $ 5", "costs $ 5"),
+            ("a$", "a$"),
+        ] {
+            assert_eq!(expand(input).unwrap(), expected, "{input}");
+        }
+
+        for input in ["${UNSET:?err}", "${EMPTY:?err}", "${UNSET?err}"] {
+            assert!(
+                matches!(expand(input), Err(EnvExpansionError::Required { ref message, .. }) if message == "err"),
+                "{input}"
+            );
+        }
+
+        for input in [
+            "${",
+            "${}",
+            "${ }",
+            "${ foo}",
+            "${foo }",
+            "${foo!}",
+            "${1FOO}",
+            "${FOO:=x}",
+            "${FOO=x}",
+            "${FOO",
+        ] {
+            assert!(
+                matches!(expand(input), Err(EnvExpansionError::Parse { .. })),
+                "{input}"
+            );
+        }
+    }
+
     #[cfg(windows)]
     #[test]
     fn test_single_var_windows() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("USERNAME", "charlie");
         }
@@ -219,6 +1563,7 @@ mod tests {
     #[cfg(windows)]
     #[test]
     fn test_multiple_vars_windows() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("USERNAME", "charlie");
             std::env::set_var("APPDATA", "C:\\Users\\charlie\\AppData");
@@ -231,6 +1576,7 @@ mod tests {
     #[cfg(windows)]
     #[test]
     fn test_missing_var_windows() {
+        let _env = env_lock();
         unsafe {
             std::env::remove_var("DOES_NOT_EXIST");
         }
@@ -240,12 +1586,16 @@ mod tests {
     }
 }
 
-#[cfg(feature = "regex")]
+#[cfg(all(test, feature = "regex"))]
 mod regex_tests {
-    use super::regex::expand_env_vars;
+    use super::regex::{expand_env_vars, expand_env_vars_cow, expand_env_vars_strict, expand_with};
+    use super::{EnvExpansionError, env_lock};
+    use std::borrow::Cow;
+    use std::collections::HashMap;
 
     #[test]
     fn test_single_var_unix_regex() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("USER", "alice");
         }
@@ -256,6 +1606,7 @@ mod regex_tests {
 
     #[test]
     fn test_braced_var_unix_regex() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("HOME", "/home/alice");
         }
@@ -266,6 +1617,7 @@ mod regex_tests {
 
     #[test]
     fn test_multiple_vars_unix_regex() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("USER", "bob");
             std::env::set_var("SHELL", "/bin/bash");
@@ -277,6 +1629,7 @@ mod regex_tests {
 
     #[test]
     fn test_missing_var_unix_regex() {
+        let _env = env_lock();
         unsafe {
             std::env::remove_var("DOES_NOT_EXIST");
         }
@@ -285,9 +1638,71 @@ mod regex_tests {
         assert_eq!(output, "This is ");
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_strict_missing_var_unix_regex() {
+        let _env = env_lock();
+        unsafe {
+            std::env::remove_var("STRICT_DOES_NOT_EXIST");
+        }
+        let input = "Path: ${STRICT_DOES_NOT_EXIST}/bin";
+        let err = expand_env_vars_strict(input).unwrap_err();
+        assert!(
+            matches!(err, EnvExpansionError::MissingVar { ref name, .. } if name == "STRICT_DOES_NOT_EXIST")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_operators_unix_regex() {
+        let vars = HashMap::from([("REGEX_OP_SET", "1"), ("REGEX_OP_EMPTY", "")]);
+        let input = "${REGEX_OP_SET:+--verbose} [${REGEX_OP_EMPTY:+x}] [${REGEX_OP_EMPTY+x}]";
+        assert_eq!(expand_with(input, &vars).unwrap(), "--verbose [] [x]");
+        let input = "${REGEX_OP_UNSET:-$REGEX_OP_SET} [${REGEX_OP_EMPTY-x}]";
+        assert_eq!(expand_with(input, &vars).unwrap(), "1 []");
+        let err = expand_with("${REGEX_OP_UNSET:?required}", &vars).unwrap_err();
+        assert!(
+            matches!(err, EnvExpansionError::Required { ref message, .. } if message == "required")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_env_vars_cow_unix_regex() {
+        let _env = env_lock();
+        unsafe {
+            std::env::set_var("COW_REGEX_USER", "erin");
+        }
+        let input = "no placeholders, $ 5";
+        assert!(matches!(expand_env_vars_cow(input).unwrap(), Cow::Borrowed(s) if s == input));
+        assert_eq!(expand_env_vars_cow("$COW_REGEX_USER").unwrap(), "erin");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_not_unicode_unix_regex() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let _env = env_lock();
+        unsafe {
+            std::env::set_var("REGEX_NOT_UNICODE", std::ffi::OsStr::from_bytes(b"\xff"));
+        }
+        let err = expand_env_vars("${REGEX_NOT_UNICODE:-x}").unwrap_err();
+        assert!(matches!(err, EnvExpansionError::NotUnicode { .. }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_with_map_unix_regex() {
+        let vars = HashMap::from([("USER", "dora")]);
+        let input = "$USER ${SHELL:-sh}";
+        assert_eq!(expand_with(input, &vars).unwrap(), "dora sh");
+    }
+
     #[cfg(windows)]
     #[test]
     fn test_single_var_windows_regex() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("USERNAME", "charlie");
         }
@@ -299,6 +1714,7 @@ mod regex_tests {
     #[cfg(windows)]
     #[test]
     fn test_multiple_vars_windows_regex() {
+        let _env = env_lock();
         unsafe {
             std::env::set_var("USERNAME", "charlie");
             std::env::set_var("APPDATA", "C:\\Users\\charlie\\AppData");
@@ -311,6 +1727,7 @@ mod regex_tests {
     #[cfg(windows)]
     #[test]
     fn test_missing_var_windows_regex() {
+        let _env = env_lock();
         unsafe {
             std::env::remove_var("DOES_NOT_EXIST");
         }
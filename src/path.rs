@@ -0,0 +1,292 @@
+//! Expansion of filesystem paths.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{self, Path, PathBuf};
+
+use crate::ast::{self, Node, Part};
+use crate::template::Expander;
+use crate::{EnvExpansionError, Options, VarSource};
+
+/// The variable holding the current user's home directory.
+#[cfg(windows)]
+const HOME: &str = "USERPROFILE";
+#[cfg(not(windows))]
+const HOME: &str = "HOME";
+
+/// The variable that `~<user>` stands for, if any: `~` is the home directory, `~+` the
+/// current directory and `~-` the previous one.
+pub(crate) fn tilde_var(user: &str) -> Option<&'static str> {
+    match user {
+        "" => Some(HOME),
+        "+" => Some("PWD"),
+        "-" => Some("OLDPWD"),
+        _ => None,
+    }
+}
+
+/// Expands `path` with a single [`Expander`], after replacing a leading `~` or `~user`.
+///
+/// Literal parts of the path are kept byte for byte. Only the separators where an expanded
+/// value meets another separator are collapsed, so values that start or end with a separator,
+/// or are empty, neither replace the path so far nor leave doubled separators behind.
+pub(crate) fn expand(
+    options: &Options,
+    path: &Path,
+    source: &dyn VarSource,
+) -> Result<PathBuf, EnvExpansionError> {
+    let input = path.as_os_str();
+    let parts = ast::parse_os(input, options)?;
+    let mut parts = parts.as_slice();
+    let mut expander = Expander::new(options, input.as_encoded_bytes(), source, true);
+    let mut out = Joined::default();
+
+    // A tilde prefix is only resolved when the whole first component is literal text
+    if let [Part::Node(Node::Text { text, .. }), others @ ..] = parts
+        && text.starts_with('~')
+    {
+        let end = text.find(path::is_separator);
+        let (user, rest) = text.split_at(end.unwrap_or(text.len()));
+        let whole = end.is_some() || others.is_empty();
+        if let Some(home) = whole.then(|| tilde(user, source)).flatten() {
+            out.value(&home);
+            out.literal(OsStr::new(rest));
+            parts = others;
+        }
+    }
+
+    for part in parts {
+        match part {
+            Part::Raw(raw) => out.literal(raw),
+            Part::Node(Node::Text { text, .. }) => out.literal(OsStr::new(text.as_ref())),
+            Part::Node(node) => {
+                let mut val = OsString::new();
+                expander.render_node(node, &mut val)?;
+                match node {
+                    Node::Escape { .. } => out.literal(&val),
+                    _ => out.value(&val),
+                }
+            }
+        }
+    }
+    expander.finish()?;
+    Ok(out.path.into())
+}
+
+/// A path being put together from literal text and expanded values.
+#[derive(Default)]
+struct Joined {
+    path: OsString,
+    /// Whether the separator at the end of `path` absorbs the separators that follow it,
+    /// because it came from a value or is followed only by empty values.
+    loose: bool,
+}
+
+impl Joined {
+    fn literal(&mut self, text: &OsStr) {
+        let text = if self.loose {
+            trim_separators(text)
+        } else {
+            text
+        };
+        if !text.is_empty() {
+            self.path.push(text);
+            self.loose = false;
+        }
+    }
+
+    fn value(&mut self, val: &OsStr) {
+        let after_separator = ends_with_separator(&self.path);
+        let val = if after_separator {
+            trim_separators(val)
+        } else {
+            val
+        };
+        if val.is_empty() {
+            self.loose |= after_separator;
+        } else {
+            self.path.push(val);
+            self.loose = ends_with_separator(val);
+        }
+    }
+}
+
+fn is_separator_byte(b: u8) -> bool {
+    b.is_ascii() && path::is_separator(b.into())
+}
+
+fn ends_with_separator(s: &OsStr) -> bool {
+    s.as_encoded_bytes()
+        .last()
+        .is_some_and(|&b| is_separator_byte(b))
+}
+
+/// Strips the separators at the start of `s`.
+fn trim_separators(s: &OsStr) -> &OsStr {
+    let bytes = s.as_encoded_bytes();
+    let start = bytes
+        .iter()
+        .position(|&b| !is_separator_byte(b))
+        .unwrap_or(bytes.len());
+    // SAFETY: the bytes come from `as_encoded_bytes` and are only split after ASCII
+    // separators, which are valid non-empty UTF-8.
+    unsafe { OsStr::from_encoded_bytes_unchecked(&bytes[start..]) }
+}
+
+/// Resolves `prefix` if it is `~`, `~+`, `~-` or `~user`. Unknown users are left alone.
+fn tilde(prefix: &str, source: &dyn VarSource) -> Option<OsString> {
+    let user = prefix.strip_prefix('~')?;
+    match tilde_var(user) {
+        Some(name) => source.var_os(name).map(|home| home.into_owned()),
+        None => user_home(user),
+    }
+}
+
+/// Looks up the home directory of `user` in `/etc/passwd`.
+#[cfg(unix)]
+pub(crate) fn user_home(user: &str) -> Option<OsString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let passwd = std::fs::read("/etc/passwd").ok()?;
+    passwd.split(|&b| b == b'\n').find_map(|line| {
+        // name:password:uid:gid:gecos:home:shell
+        let mut fields = line.split(|&b| b == b':');
+        if fields.next()? != user.as_bytes() {
+            return None;
+        }
+        let home = fields.nth(4)?;
+        Some(OsStr::from_bytes(home).to_os_string())
+    })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn user_home(_user: &str) -> Option<OsString> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Syntax;
+    use std::collections::HashMap;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_path() {
+        let vars = HashMap::from([(HOME, "/home/alice"), ("APP", "demo"), ("ABS", "/abs")]);
+        let options = Options::new().syntax(Syntax::Unix);
+        let expand = |path: &str| expand(&options, Path::new(path), &vars).unwrap();
+        assert_eq!(
+            expand("~/cache/${APP}/data"),
+            Path::new("/home/alice/cache/demo/data")
+        );
+        assert_eq!(expand("~"), Path::new("/home/alice"));
+        assert_eq!(expand("/srv/$APP"), Path::new("/srv/demo"));
+        assert_eq!(expand("$HOME/.$APP"), Path::new("/home/alice/.demo"));
+        assert_eq!(expand("data/$ABS/x"), Path::new("data/abs/x"));
+        assert_eq!(expand("a/~/b"), Path::new("a/~/b"));
+        assert_eq!(
+            expand("~no_such_user_here/x"),
+            Path::new("~no_such_user_here/x")
+        );
+        let strict = options.clone().strict();
+        assert!(super::expand(&strict, Path::new("/$UNSET"), &vars).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_path_separators_in_placeholders() {
+        let vars = HashMap::from([(HOME, "/home/alice")]);
+        let options = Options::new().syntax(Syntax::Unix);
+        let expand = |options: &Options, path: &str| expand(options, Path::new(path), &vars);
+        assert_eq!(
+            expand(&options, "${DIR:-/var/lib}/app").unwrap(),
+            Path::new("/var/lib/app")
+        );
+        assert_eq!(
+            expand(&options, "${XDG_CACHE_HOME:-$HOME/.cache}/app").unwrap(),
+            Path::new("/home/alice/.cache/app")
+        );
+        let strict = options.clone().strict_parse(true);
+        assert_eq!(
+            expand(&strict, "${DIR:-/var/lib}/app").unwrap(),
+            Path::new("/var/lib/app")
+        );
+        assert!(expand(&strict, "${DIR:-/var/lib/app").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_path_keeps_root() {
+        let vars = HashMap::from([("EMPTY", ""), ("DIR", "/opt/")]);
+        let options = Options::new().syntax(Syntax::Unix);
+        let expand = |path: &str| expand(&options, Path::new(path), &vars).unwrap();
+        assert_eq!(expand("$UNSET/bin"), Path::new("/bin"));
+        assert_eq!(expand("${EMPTY}/bin"), Path::new("/bin"));
+        assert_eq!(expand("/"), Path::new("/"));
+        assert_eq!(expand("$DIR/bin"), Path::new("/opt/bin"));
+        assert_eq!(expand("a/$UNSET/b/").as_os_str(), "a/b/");
+        assert_eq!(expand("a/$UNSET$EMPTY//b").as_os_str(), "a/b");
+        assert_eq!(expand("$DIR").as_os_str(), "/opt/");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_path_keeps_literal_text() {
+        let vars = HashMap::from([("DIR", "/opt/"), ("X", "x")]);
+        let options = Options::new().syntax(Syntax::Unix);
+        let expand = |path: &str| expand(&options, Path::new(path), &vars).unwrap();
+        assert_eq!(expand("./a/./b/").as_os_str(), "./a/./b/");
+        assert_eq!(expand("a//b/../$X/").as_os_str(), "a//b/../x/");
+        assert_eq!(expand("$DIR/./$X//").as_os_str(), "/opt/./x//");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_path_single_expansion() {
+        let vars: HashMap<&str, &str> = HashMap::new();
+        let options = Options::new().syntax(Syntax::Unix);
+        let expand = |options: &Options, path: &str| expand(options, Path::new(path), &vars);
+        assert_eq!(
+            expand(&options, "/d/${A:=x}/$A").unwrap().as_os_str(),
+            "/d/x/x"
+        );
+
+        let collect = options.clone().on_missing(crate::OnMissing::Collect);
+        let Err(EnvExpansionError::MissingVars(missing)) = expand(&collect, "/d/$M1/$M2") else {
+            panic!("expected MissingVars");
+        };
+        let names: Vec<_> = missing.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["M1", "M2"]);
+
+        let Err(EnvExpansionError::MissingVar { location, .. }) =
+            expand(&options.clone().strict(), "/d/${M1}/x")
+        else {
+            panic!("expected MissingVar");
+        };
+        assert_eq!(
+            (location.column, location.snippet.as_str()),
+            (4, "/d/${M1}/x")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_path_user() {
+        let vars: HashMap<&str, &str> = HashMap::new();
+        let root = expand(&Options::new(), Path::new("~root/x"), &vars).unwrap();
+        if let Some(home) = user_home("root") {
+            assert_eq!(root, Path::new(&home).join("x"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_path_not_unicode() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"/data/\xff/$APP"));
+        let vars = HashMap::from([("APP", "demo")]);
+        let out = expand(&Options::new(), path, &vars).unwrap();
+        assert_eq!(out.as_os_str().as_bytes(), b"/data/\xff/demo");
+    }
+}
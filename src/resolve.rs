@@ -0,0 +1,372 @@
+//! Resolution of sets of variables that refer to each other.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{EnvExpansionError, OnMissing, Options, Template, Unresolved, VarSource};
+
+/// Expands every value in `vars`, after the values it refers to. Names that are not in `vars`
+/// are looked up in `source`. With `Syntax::Kubernetes`, values are expanded in order
+/// instead; see [`in_order`].
+pub(crate) fn resolve(
+    options: &Options,
+    vars: Vec<(String, String)>,
+    source: &dyn VarSource,
+) -> Result<Vec<(String, String)>, EnvExpansionError> {
+    // Unset names are reported together with any cycles rather than one at a time, unless
+    // they are to be kept as written
+    let options = match options.on_missing {
+        OnMissing::Keep => options.clone(),
+        _ => options.clone().on_missing(OnMissing::Collect),
+    };
+    if options.syntax.paren() {
+        return in_order(&options, vars, source);
+    }
+    let vars = dedup(vars);
+    let templates = vars
+        .iter()
+        .map(|(_, value)| options.parse(value))
+        .collect::<Result<Vec<_>, _>>()?;
+    let index: HashMap<&str, usize> = vars
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| (name.as_str(), i))
+        .collect();
+    // An entry referring to its own name, as in `PATH=/opt/bin:$PATH`, gets the value from
+    // `source` rather than depending on itself
+    let deps: Vec<BTreeSet<usize>> = templates
+        .iter()
+        .enumerate()
+        .map(|(i, template)| {
+            template
+                .names()
+                .into_iter()
+                .filter_map(|name| index.get(name).copied())
+                .filter(|&dep| dep != i)
+                .collect()
+        })
+        .collect();
+
+    let mut resolved: HashMap<&str, String> = HashMap::new();
+    let mut unresolved = Vec::new();
+    for i in order(&deps) {
+        let name = vars[i].0.as_str();
+        let value = render(&templates[i], name, &resolved, source, &mut unresolved)?;
+        resolved.insert(name, value);
+    }
+
+    let cycles = cycles(&deps, |i| resolved.contains_key(vars[i].0.as_str()))
+        .into_iter()
+        .map(|cycle| cycle.into_iter().map(|i| vars[i].0.clone()).collect())
+        .collect::<Vec<Vec<String>>>();
+    if !cycles.is_empty() || !unresolved.is_empty() {
+        return Err(EnvExpansionError::Unresolvable { cycles, unresolved });
+    }
+
+    Ok(vars
+        .iter()
+        .map(|(name, _)| {
+            let value = resolved.remove(name.as_str()).unwrap_or_default();
+            (name.clone(), value)
+        })
+        .collect())
+}
+
+/// Expands `vars` in order, like Kubernetes does for the `env` of a container: each value only
+/// sees the entries before it, then `source`, so forward references are left unresolved.
+fn in_order(
+    options: &Options,
+    vars: Vec<(String, String)>,
+    source: &dyn VarSource,
+) -> Result<Vec<(String, String)>, EnvExpansionError> {
+    let mut resolved: HashMap<&str, String> = HashMap::new();
+    let mut unresolved = Vec::new();
+    for (name, value) in &vars {
+        let template = options.parse(value)?;
+        let value = render(&template, name, &resolved, source, &mut unresolved)?;
+        resolved.insert(name, value);
+    }
+    if !unresolved.is_empty() {
+        return Err(EnvExpansionError::Unresolvable {
+            cycles: Vec::new(),
+            unresolved,
+        });
+    }
+
+    let mut resolved: HashMap<String, String> = resolved
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    Ok(dedup(vars)
+        .into_iter()
+        .map(|(name, _)| {
+            let value = resolved.remove(&name).unwrap_or_default();
+            (name, value)
+        })
+        .collect())
+}
+
+/// Renders the value of the entry `name`. Values resolved so far are final, so they are not
+/// expanded again. References to names that are set nowhere are added to `unresolved` and
+/// the entry expands to nothing, so entries that depend on it report their own problems.
+fn render(
+    template: &Template,
+    name: &str,
+    resolved: &HashMap<&str, String>,
+    source: &dyn VarSource,
+    unresolved: &mut Vec<Unresolved>,
+) -> Result<String, EnvExpansionError> {
+    match template.render_expanded(resolved, source) {
+        Err(EnvExpansionError::MissingVars(missing)) => {
+            unresolved.extend(missing.into_iter().map(|missing| Unresolved {
+                entry: name.to_string(),
+                name: missing.name,
+                location: missing.location,
+            }));
+            Ok(String::new())
+        }
+        result => result,
+    }
+}
+
+/// Orders the entries so each comes after its dependencies, preferring earlier entries when
+/// several are ready. Entries in or depending on a cycle are left out.
+fn order(deps: &[BTreeSet<usize>]) -> Vec<usize> {
+    let mut pending: Vec<usize> = deps.iter().map(BTreeSet::len).collect();
+    let mut dependents = vec![Vec::new(); deps.len()];
+    for (i, deps) in deps.iter().enumerate() {
+        for &dep in deps {
+            dependents[dep].push(i);
+        }
+    }
+
+    let mut ready: BTreeSet<usize> = (0..deps.len()).filter(|&i| pending[i] == 0).collect();
+    let mut order = Vec::with_capacity(deps.len());
+    while let Some(i) = ready.pop_first() {
+        order.push(i);
+        for &dependent in &dependents[i] {
+            pending[dependent] -= 1;
+            if pending[dependent] == 0 {
+                ready.insert(dependent);
+            }
+        }
+    }
+    order
+}
+
+/// Finds the cycles among the entries that were not resolved, each listed from its first
+/// entry back to itself.
+fn cycles(deps: &[BTreeSet<usize>], resolved: impl Fn(usize) -> bool) -> Vec<Vec<usize>> {
+    /// Not visited yet, on the current path, or done.
+    #[derive(Clone, Copy, PartialEq)]
+    enum State {
+        New,
+        Active,
+        Done,
+    }
+
+    fn visit(
+        i: usize,
+        deps: &[BTreeSet<usize>],
+        state: &mut [State],
+        path: &mut Vec<usize>,
+        cycles: &mut Vec<Vec<usize>>,
+    ) {
+        state[i] = State::Active;
+        path.push(i);
+        for &dep in &deps[i] {
+            match state[dep] {
+                State::New => visit(dep, deps, state, path, cycles),
+                State::Active => {
+                    let start = path.iter().position(|&j| j == dep).unwrap_or_default();
+                    let mut cycle = path[start..].to_vec();
+                    cycle.push(dep);
+                    cycles.push(cycle);
+                }
+                State::Done => {}
+            }
+        }
+        path.pop();
+        state[i] = State::Done;
+    }
+
+    let mut state: Vec<State> = (0..deps.len())
+        .map(|i| if resolved(i) { State::Done } else { State::New })
+        .collect();
+    let mut cycles = Vec::new();
+    for i in 0..deps.len() {
+        if state[i] == State::New {
+            visit(i, deps, &mut state, &mut Vec::new(), &mut cycles);
+        }
+    }
+    cycles
+}
+
+/// Turns `vars` into a list without duplicate names, where the last value for a name wins.
+pub(crate) fn dedup<K, V>(vars: impl IntoIterator<Item = (K, V)>) -> Vec<(String, String)>
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    let mut out: Vec<(String, String)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for (name, value) in vars {
+        let (name, value) = (name.into(), value.into());
+        match index.get(&name) {
+            Some(&i) => out[i].1 = value,
+            None => {
+                index.insert(name.clone(), out.len());
+                out.push((name, value));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Escape, Syntax};
+
+    fn unix() -> Options {
+        Options::new().syntax(Syntax::Unix)
+    }
+
+    fn resolve(vars: &[(&str, &str)]) -> Result<Vec<(String, String)>, EnvExpansionError> {
+        let outer = HashMap::from([("OUTER", "/outer")]);
+        super::resolve(&unix(), dedup(vars.iter().copied()), &outer)
+    }
+
+    #[test]
+    fn test_resolve_out_of_order() {
+        let resolved = resolve(&[
+            ("BIN", "$BASE/bin"),
+            ("LOG", "${DATA:-/tmp}/log"),
+            ("BASE", "${OUTER}/opt"),
+            ("DATA", "$BASE/data"),
+            ("PLAIN", "as is"),
+            ("PLAIN", "last wins"),
+        ])
+        .unwrap();
+        assert_eq!(
+            resolved,
+            [
+                ("BIN".to_string(), "/outer/opt/bin".to_string()),
+                ("LOG".to_string(), "/outer/opt/data/log".to_string()),
+                ("BASE".to_string(), "/outer/opt".to_string()),
+                ("DATA".to_string(), "/outer/opt/data".to_string()),
+                ("PLAIN".to_string(), "last wins".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_cycles_and_unresolved() {
+        let err = resolve(&[
+            ("A", "$B"),
+            ("B", "${C:-x}"),
+            ("C", "$A"),
+            ("D", "$A/$UNSET"),
+            ("SELF", "$SELF"),
+            ("E", "$UNSET_E ${OPTIONAL:-}"),
+        ])
+        .unwrap_err();
+        let EnvExpansionError::Unresolvable { cycles, unresolved } = &err else {
+            panic!("expected Unresolvable, got {err:?}");
+        };
+        assert_eq!(cycles, &[["A", "B", "C", "A"]]);
+        let unresolved: Vec<_> = unresolved
+            .iter()
+            .map(|u| (u.entry.as_str(), u.name.as_str(), u.location.span.start))
+            .collect();
+        assert_eq!(unresolved, [("SELF", "SELF", 0), ("E", "UNSET_E", 0)]);
+        assert_eq!(
+            err.to_string(),
+            "Unresolvable variables: cycle A -> B -> C -> A; SELF refers to unset SELF; \
+             E refers to unset UNSET_E"
+        );
+    }
+
+    #[test]
+    fn test_resolve_self_reference() {
+        let outer = HashMap::from([("PATH", "/bin")]);
+        let vars = dedup([("PATH", "/opt/bin:$PATH"), ("MAN", "$PATH")]);
+        assert_eq!(
+            super::resolve(&unix(), vars, &outer).unwrap(),
+            [
+                ("PATH".to_string(), "/opt/bin:/bin".to_string()),
+                ("MAN".to_string(), "/opt/bin:/bin".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_does_not_expand_twice() {
+        let options = unix().escape(Escape::Double).recursive(4);
+        let outer = HashMap::from([("OUTER", "$B")]);
+        let vars = dedup([("A", "$$B"), ("B", "oops"), ("C", "$A"), ("D", "$OUTER")]);
+        assert_eq!(
+            super::resolve(&options, vars, &outer).unwrap(),
+            [
+                ("A".to_string(), "$B".to_string()),
+                ("B".to_string(), "oops".to_string()),
+                ("C".to_string(), "$B".to_string()),
+                ("D".to_string(), "oops".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_keep() {
+        let options = unix().on_missing(OnMissing::Keep);
+        let vars = dedup([("A", "$UNSET/$B"), ("B", "${ALSO_UNSET}x")]);
+        assert_eq!(
+            super::resolve(&options, vars, &HashMap::<&str, &str>::new()).unwrap(),
+            [
+                ("A".to_string(), "$UNSET/${ALSO_UNSET}x".to_string()),
+                ("B".to_string(), "${ALSO_UNSET}x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_kubernetes_in_order() {
+        let outer = HashMap::from([("HOST", "db"), ("PATH", "/bin")]);
+        let resolve = |options: &Options, vars: &[(&str, &str)]| {
+            let vars = vars.iter().map(|&(n, v)| (n.to_string(), v.to_string()));
+            super::resolve(options, vars.collect(), &outer)
+        };
+        let options = Options::kubernetes();
+        assert_eq!(
+            resolve(
+                &options,
+                &[
+                    ("A", "$(B)"),
+                    ("B", "x"),
+                    ("C", "$(B)$(UNSET)"),
+                    ("PATH", "/opt:$(PATH)"),
+                    ("B", "y"),
+                    ("D", "$(B)$$(B)"),
+                ]
+            )
+            .unwrap(),
+            [
+                ("A".to_string(), "$(B)".to_string()),
+                ("B".to_string(), "y".to_string()),
+                ("C".to_string(), "x$(UNSET)".to_string()),
+                ("PATH".to_string(), "/opt:/bin".to_string()),
+                ("D".to_string(), "y$(B)".to_string()),
+            ]
+        );
+
+        let strict = options.on_missing(OnMissing::Error);
+        let err = resolve(&strict, &[("A", "$(B)"), ("B", "x")]).unwrap_err();
+        let EnvExpansionError::Unresolvable { cycles, unresolved } = &err else {
+            panic!("expected Unresolvable, got {err:?}");
+        };
+        assert!(cycles.is_empty());
+        assert_eq!(
+            (unresolved[0].entry.as_str(), unresolved[0].name.as_str()),
+            ("A", "B")
+        );
+    }
+}
@@ -0,0 +1,304 @@
+//! Sources of variable values.
+
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+/// Somewhere to look up variable values.
+///
+/// Implemented for the process environment ([`Env`]), `HashMap` and `BTreeMap` with string
+/// keys and values, and closures wrapped with [`from_fn`]. Sources can be stacked with
+/// [`Layered`].
+pub trait VarSource {
+    /// Returns the value of `name`, or `None` if it is not set or not valid Unicode.
+    fn var(&self, name: &str) -> Option<Cow<'_, str>>;
+
+    /// Returns the value of `name` even if it is not valid Unicode, or `None` if it is not set.
+    ///
+    /// The default implementation calls [`VarSource::var`], which is enough for sources that
+    /// only hold Unicode values.
+    fn var_os(&self, name: &str) -> Option<Cow<'_, OsStr>> {
+        self.var(name).map(|val| match val {
+            Cow::Borrowed(val) => Cow::Borrowed(OsStr::new(val)),
+            Cow::Owned(val) => Cow::Owned(val.into()),
+        })
+    }
+}
+
+/// The process environment, read with [`std::env::var`] and [`std::env::var_os`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Env;
+
+impl VarSource for Env {
+    fn var(&self, name: &str) -> Option<Cow<'_, str>> {
+        env::var(name).ok().map(Cow::Owned)
+    }
+
+    fn var_os(&self, name: &str) -> Option<Cow<'_, OsStr>> {
+        env::var_os(name).map(Cow::Owned)
+    }
+}
+
+impl<K, V, S> VarSource for HashMap<K, V, S>
+where
+    K: Borrow<str> + Hash + Eq,
+    V: AsRef<str>,
+    S: BuildHasher,
+{
+    fn var(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.get(name).map(|val| Cow::Borrowed(val.as_ref()))
+    }
+}
+
+impl<K, V> VarSource for BTreeMap<K, V>
+where
+    K: Borrow<str> + Ord,
+    V: AsRef<str>,
+{
+    fn var(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.get(name).map(|val| Cow::Borrowed(val.as_ref()))
+    }
+}
+
+impl<T: VarSource + ?Sized> VarSource for &T {
+    fn var(&self, name: &str) -> Option<Cow<'_, str>> {
+        (**self).var(name)
+    }
+
+    fn var_os(&self, name: &str) -> Option<Cow<'_, OsStr>> {
+        (**self).var_os(name)
+    }
+}
+
+impl<T: VarSource + ?Sized> VarSource for Box<T> {
+    fn var(&self, name: &str) -> Option<Cow<'_, str>> {
+        (**self).var(name)
+    }
+
+    fn var_os(&self, name: &str) -> Option<Cow<'_, OsStr>> {
+        (**self).var_os(name)
+    }
+}
+
+/// A [`VarSource`] backed by a closure. Created with [`from_fn`].
+#[derive(Debug, Clone, Copy)]
+pub struct FromFn<F>(F);
+
+/// Creates a [`VarSource`] that looks up values by calling `f`.
+///
+/// ```
+/// use expand_env_vars::{Options, Syntax, from_fn};
+///
+/// let source = from_fn(|name| Some(name.to_lowercase()));
+/// let options = Options::new().syntax(Syntax::Unix);
+/// assert_eq!(options.expand_with("$HELLO", &source).unwrap(), "hello");
+/// ```
+pub fn from_fn<F>(f: F) -> FromFn<F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    FromFn(f)
+}
+
+impl<F> VarSource for FromFn<F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    fn var(&self, name: &str) -> Option<Cow<'_, str>> {
+        (self.0)(name).map(Cow::Owned)
+    }
+}
+
+/// A stack of named sources where the first layer defining a variable wins.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use expand_env_vars::{Layered, VarSource};
+///
+/// let overrides = HashMap::from([("PORT", "9090")]);
+/// let defaults = HashMap::from([("PORT", "8080"), ("HOST", "localhost")]);
+/// let layered = Layered::new()
+///     .layer("cli", overrides)
+///     .layer("defaults", defaults);
+///
+/// assert_eq!(layered.var("PORT").as_deref(), Some("9090"));
+/// assert_eq!(layered.which("PORT"), Some("cli"));
+/// assert_eq!(layered.which("HOST"), Some("defaults"));
+/// assert_eq!(layered.defined_in("PORT"), ["cli", "defaults"]);
+/// ```
+#[derive(Default)]
+pub struct Layered<'a> {
+    layers: Vec<(String, Box<dyn VarSource + 'a>)>,
+}
+
+impl<'a> Layered<'a> {
+    /// Creates an empty stack.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a layer with lower precedence than every layer added so far.
+    pub fn layer(mut self, label: impl Into<String>, source: impl VarSource + 'a) -> Self {
+        self.push(label, source);
+        self
+    }
+
+    /// Adds a layer with lower precedence than every layer added so far.
+    pub fn push(&mut self, label: impl Into<String>, source: impl VarSource + 'a) {
+        self.layers.push((label.into(), Box::new(source)));
+    }
+
+    /// Returns the value of `name` together with the label of the layer that supplied it, or
+    /// `None` if it is unset or the value in that layer is not valid Unicode.
+    pub fn lookup(&self, name: &str) -> Option<(&str, Cow<'_, str>)> {
+        let (label, val) = self.lookup_os(name)?;
+        let val = match val {
+            Cow::Borrowed(val) => Cow::Borrowed(val.to_str()?),
+            Cow::Owned(val) => Cow::Owned(val.into_string().ok()?),
+        };
+        Some((label, val))
+    }
+
+    /// Like [`Layered::lookup`], but returns values that are not valid Unicode too.
+    pub fn lookup_os(&self, name: &str) -> Option<(&str, Cow<'_, OsStr>)> {
+        self.layers
+            .iter()
+            .find_map(|(label, source)| Some((label.as_str(), source.var_os(name)?)))
+    }
+
+    /// Returns the label of the layer that supplies `name`, even if its value there is not
+    /// valid Unicode.
+    pub fn which(&self, name: &str) -> Option<&str> {
+        self.lookup_os(name).map(|(label, _)| label)
+    }
+
+    /// Returns the labels of every layer that defines `name`, in precedence order. All but the
+    /// first are shadowed.
+    pub fn defined_in(&self, name: &str) -> Vec<&str> {
+        self.layers
+            .iter()
+            .filter(|(_, source)| source.var_os(name).is_some())
+            .map(|(label, _)| label.as_str())
+            .collect()
+    }
+}
+
+impl VarSource for Layered<'_> {
+    fn var(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.lookup(name).map(|(_, val)| val)
+    }
+
+    fn var_os(&self, name: &str) -> Option<Cow<'_, OsStr>> {
+        self.lookup_os(name).map(|(_, val)| val)
+    }
+}
+
+impl fmt::Debug for Layered<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Layered")
+            .field(
+                "layers",
+                &self
+                    .layers
+                    .iter()
+                    .map(|(label, _)| label)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_map_source() {
+        let owned: HashMap<String, String> = [("A".to_string(), "1".to_string())].into();
+        assert_eq!(owned.var("A").as_deref(), Some("1"));
+        assert_eq!(owned.var("B"), None);
+
+        let borrowed: HashMap<&str, &str> = [("A", "1")].into();
+        assert_eq!(borrowed.var("A").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_btree_map_source() {
+        let map: BTreeMap<&str, String> = [("A", "1".to_string())].into();
+        assert_eq!(map.var("A").as_deref(), Some("1"));
+        assert_eq!(map.var("B"), None);
+    }
+
+    #[test]
+    fn test_from_fn_source() {
+        let source = from_fn(|name| (name == "A").then(|| "1".to_string()));
+        assert_eq!(source.var("A").as_deref(), Some("1"));
+        assert_eq!(source.var("B"), None);
+
+        let boxed: Box<dyn VarSource> = Box::new(source);
+        assert_eq!(boxed.var("A").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_layered_precedence() {
+        let cli = HashMap::from([("A", "cli")]);
+        let dotenv = BTreeMap::from([("A", "dotenv"), ("B", "dotenv")]);
+        let defaults = from_fn(|name| Some(format!("default-{name}")));
+        let layered = Layered::new()
+            .layer("cli", cli)
+            .layer(".env", &dotenv)
+            .layer("defaults", defaults);
+
+        assert_eq!(layered.lookup("A"), Some(("cli", Cow::Borrowed("cli"))));
+        assert_eq!(layered.which("B"), Some(".env"));
+        assert_eq!(layered.var("C").as_deref(), Some("default-C"));
+        assert_eq!(layered.defined_in("A"), ["cli", ".env", "defaults"]);
+        assert_eq!(
+            format!("{layered:?}"),
+            r#"Layered { layers: ["cli", ".env", "defaults"] }"#
+        );
+        assert_eq!(Layered::new().which("A"), None);
+    }
+
+    #[test]
+    fn test_var_os() {
+        let map = HashMap::from([("A", "1")]);
+        assert_eq!(map.var_os("A").as_deref(), Some(OsStr::new("1")));
+        assert_eq!(map.var_os("B"), None);
+        let layered = Layered::new()
+            .layer("map", map)
+            .layer("fn", from_fn(|_| Some("2".to_string())));
+        assert_eq!(layered.var_os("A").as_deref(), Some(OsStr::new("1")));
+        assert_eq!(layered.var_os("B").as_deref(), Some(OsStr::new("2")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_layered_not_unicode() {
+        use std::os::unix::ffi::OsStrExt;
+
+        struct Raw;
+        impl VarSource for Raw {
+            fn var(&self, _name: &str) -> Option<Cow<'_, str>> {
+                None
+            }
+
+            fn var_os(&self, name: &str) -> Option<Cow<'_, OsStr>> {
+                (name == "X").then(|| Cow::Borrowed(OsStr::from_bytes(b"\xff")))
+            }
+        }
+
+        let layered = Layered::new()
+            .layer("raw", Raw)
+            .layer("map", HashMap::from([("X", "ok")]));
+        assert_eq!(layered.which("X"), Some("raw"));
+        assert_eq!(layered.defined_in("X"), ["raw", "map"]);
+        assert_eq!(layered.lookup("X"), None);
+        assert_eq!(layered.var("X"), None);
+        let (label, val) = layered.lookup_os("X").unwrap();
+        assert_eq!((label, val.as_bytes()), ("raw", &b"\xff"[..]));
+    }
+}
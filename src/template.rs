@@ -0,0 +1,751 @@
+//! Templates that are parsed once and rendered many times.
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+
+use crate::ast::{self, Node, Op, Part, Var};
+use crate::path;
+use crate::{
+    Env, EnvExpansionError, Expansion, Location, Missing, OnMissing, Options, Reference, Span,
+    VarSource,
+};
+
+/// A parsed input that can be rendered against any number of variable sources.
+///
+/// Parsing splits the input into literal text and placeholders once, so rendering only
+/// has to look variables up and concatenate.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use expand_env_vars::{Options, Syntax};
+///
+/// let template = Options::new()
+///     .syntax(Syntax::Unix)
+///     .parse("${HOST:-localhost}:$PORT")
+///     .unwrap();
+///
+/// let vars = HashMap::from([("PORT", "8080")]);
+/// assert_eq!(template.render(&vars).unwrap(), "localhost:8080");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Template {
+    /// The parsed input, kept to report error locations.
+    source: String,
+    nodes: Vec<Node<'static>>,
+    options: Options,
+}
+
+impl Template {
+    /// Parses `input` with the default [`Options`], which keep malformed placeholders as
+    /// literal text. Use [`Options::parse`] to parse with other options.
+    pub fn parse(input: &str) -> Self {
+        Options::new()
+            .parse(input)
+            .expect("lenient parsing never fails")
+    }
+
+    pub(crate) fn with_options(input: &str, options: &Options) -> Result<Self, EnvExpansionError> {
+        let nodes = ast::parse(input, options)?;
+        Ok(Self {
+            source: input.to_string(),
+            nodes: nodes.into_iter().map(Node::into_owned).collect(),
+            options: options.clone(),
+        })
+    }
+
+    /// The parsed nodes. See the [`ast`](crate::ast) module.
+    pub fn nodes(&self) -> &[Node<'static>] {
+        &self.nodes
+    }
+
+    /// Returns every variable referenced by the template. See [`crate::referenced_vars`].
+    pub fn references(&self) -> Vec<Reference> {
+        let mut refs = Vec::new();
+        ast::walk(&self.nodes, &mut |node| {
+            if let Node::Var(var) = node {
+                refs.push(Reference {
+                    name: var.name.to_string(),
+                    span: var.span,
+                    op: var.op.as_ref().map(Op::kind),
+                });
+            }
+        });
+        refs
+    }
+
+    /// Returns the names of the variables referenced by the template, without duplicates, in
+    /// order of first appearance.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        ast::walk(&self.nodes, &mut |node| {
+            if let Node::Var(var) = node
+                && !names.contains(&var.name.as_ref())
+            {
+                names.push(var.name.as_ref());
+            }
+        });
+        names
+    }
+
+    /// Renders the template against the process environment.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand`].
+    pub fn render_env(&self) -> Result<String, EnvExpansionError> {
+        self.render(&Env)
+    }
+
+    /// Renders the template, looking variables up in `source`.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand`].
+    pub fn render<S: VarSource + ?Sized>(&self, source: &S) -> Result<String, EnvExpansionError> {
+        self.render_full(source).map(|expansion| expansion.value)
+    }
+
+    /// Like [`Template::render`], but also returns the variables assigned with
+    /// `${VAR:=default}`. See [`Options::expand_full`].
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand`].
+    pub fn render_full<S: VarSource + ?Sized>(
+        &self,
+        source: &S,
+    ) -> Result<Expansion, EnvExpansionError> {
+        render_full(&self.options, &self.source, &self.nodes, &source)
+    }
+
+    /// Like [`Template::render`], but allows values that are not valid Unicode. See
+    /// [`Options::expand_os`].
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Options::expand_os`].
+    pub fn render_os<S: VarSource + ?Sized>(
+        &self,
+        source: &S,
+    ) -> Result<OsString, EnvExpansionError> {
+        render_os(&self.options, &self.source, &self.nodes, &source)
+    }
+
+    /// Like [`Template::render`], but names in `expanded` are substituted without expanding
+    /// their values again. Used by [`Options::resolve`].
+    pub(crate) fn render_expanded(
+        &self,
+        expanded: &dyn VarSource,
+        source: &dyn VarSource,
+    ) -> Result<String, EnvExpansionError> {
+        let mut expander = Expander::new(&self.options, self.source.as_bytes(), source, false);
+        expander.expanded = Some(expanded);
+        let mut value = OsString::with_capacity(self.source.len());
+        expander.render(&self.nodes, &mut value)?;
+        expander.finish()?;
+        Ok(into_string(value))
+    }
+}
+
+/// Renders the nodes parsed from `input`.
+fn render_full(
+    options: &Options,
+    input: &str,
+    nodes: &[Node<'_>],
+    source: &dyn VarSource,
+) -> Result<Expansion, EnvExpansionError> {
+    let mut expander = Expander::new(options, input.as_bytes(), source, false);
+    let mut value = OsString::with_capacity(input.len());
+    expander.render(nodes, &mut value)?;
+    let assignments = expander.finish()?;
+    Ok(expansion(value, assignments))
+}
+
+/// Like [`render_full`], but allows values that are not valid Unicode.
+fn render_os(
+    options: &Options,
+    input: &str,
+    nodes: &[Node<'_>],
+    source: &dyn VarSource,
+) -> Result<OsString, EnvExpansionError> {
+    let mut expander = Expander::new(options, input.as_bytes(), source, true);
+    let mut value = OsString::with_capacity(input.len());
+    expander.render(nodes, &mut value)?;
+    expander.finish()?;
+    Ok(value)
+}
+
+/// Expands `input` without keeping the parse, for [`Options::expand_full_with`].
+pub(crate) fn expand_full(
+    options: &Options,
+    input: &str,
+    source: &dyn VarSource,
+) -> Result<Expansion, EnvExpansionError> {
+    let mut expander = Expander::new(options, input.as_bytes(), source, false);
+    let mut value = OsString::with_capacity(input.len());
+    if options.strict_parse {
+        // Malformed placeholders are reported before anything is looked up, as with a Template
+        expander.render(&ast::parse(input, options)?, &mut value)?;
+    } else {
+        // Each node is rendered as soon as it is parsed, so no nodes are collected
+        ast::parse_each(input, options, &mut |node| {
+            expander.render_node(&node, &mut value)
+        })?;
+    }
+    let assignments = expander.finish()?;
+    Ok(expansion(value, assignments))
+}
+
+/// Expands `input` without keeping the parse, for [`Options::expand_os_with`]. The valid
+/// Unicode parts of `input` share one [`Expander`], so assignments and missing variables carry
+/// across the bytes between them.
+pub(crate) fn expand_os(
+    options: &Options,
+    input: &OsStr,
+    source: &dyn VarSource,
+) -> Result<OsString, EnvExpansionError> {
+    let mut expander = Expander::new(options, input.as_encoded_bytes(), source, true);
+    let mut value = OsString::with_capacity(input.len());
+    let mut render = |part: Part<'_>| match part {
+        Part::Node(node) => expander.render_node(&node, &mut value),
+        Part::Raw(raw) => {
+            value.push(raw);
+            Ok(())
+        }
+    };
+    if options.strict_parse {
+        ast::parse_os(input, options)?
+            .into_iter()
+            .try_for_each(&mut render)?;
+    } else {
+        ast::parse_each_os(input, options, &mut render)?;
+    }
+    expander.finish()?;
+    Ok(value)
+}
+
+fn expansion(value: OsString, assignments: Vec<(String, OsString)>) -> Expansion {
+    // Every value was checked to be Unicode, so the conversions below are lossless
+    Expansion {
+        value: into_string(value),
+        assignments: assignments
+            .into_iter()
+            .map(|(name, val)| (name, into_string(val)))
+            .collect(),
+    }
+}
+
+/// Writes the template back out as source text.
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.nodes.iter().try_for_each(|node| node.fmt(f))
+    }
+}
+
+/// State for a single expansion.
+pub(crate) struct Expander<'a> {
+    options: &'a Options,
+    /// The template source, to locate errors. Only [`Options::expand_os`] and
+    /// [`Options::expand_path`] pass input that is not valid Unicode.
+    input: &'a [u8],
+    source: &'a dyn VarSource,
+    /// Whether values may be any OS string rather than only valid Unicode.
+    os: bool,
+    /// Variables whose values are being expanded, outermost first.
+    stack: Vec<String>,
+    /// The placeholder in the template that led to the value being expanded, if any. Errors
+    /// inside values are reported there.
+    outer: Option<Span>,
+    missing: Vec<Missing>,
+    /// Overlay of values assigned with `${VAR:=default}`, consulted before `source`.
+    assignments: Vec<(String, OsString)>,
+    /// Values that are already expanded, consulted after `assignments` and before `source`.
+    /// They are substituted as they are, even with [`Options::recursive`].
+    expanded: Option<&'a dyn VarSource>,
+}
+
+impl<'a> Expander<'a> {
+    pub(crate) fn new(
+        options: &'a Options,
+        input: &'a [u8],
+        source: &'a dyn VarSource,
+        os: bool,
+    ) -> Self {
+        Self {
+            options,
+            input,
+            source,
+            os,
+            stack: Vec::new(),
+            outer: None,
+            missing: Vec::new(),
+            assignments: Vec::new(),
+            expanded: None,
+        }
+    }
+
+    fn location(&self, span: Span) -> Location {
+        Location::in_bytes(self.input, self.outer.unwrap_or(span))
+    }
+
+    /// Looks `name` up, borrowing the value from the source when it allows. Only assigned
+    /// and recursively expanded values are owned.
+    fn var(&mut self, name: &str, span: Span) -> Result<Option<Cow<'a, OsStr>>, EnvExpansionError> {
+        if let Some((_, val)) = self.assignments.iter().find(|(n, _)| n == name) {
+            return Ok(Some(Cow::Owned(val.clone())));
+        }
+        if let Some(val) = self.expanded.and_then(|expanded| expanded.var_os(name)) {
+            return Ok(Some(val));
+        }
+        let source = self.source;
+        match source.var_os(name) {
+            Some(val) if !self.os && val.to_str().is_none() => Err(EnvExpansionError::NotUnicode {
+                name: name.to_string(),
+                value: val.into_owned(),
+                location: self.location(span),
+            }),
+            Some(val) if self.stack.len() < self.options.depth => self
+                .expand_value(name, &val, span)
+                .map(|val| Some(Cow::Owned(val))),
+            val => Ok(val),
+        }
+    }
+
+    /// Expands the references in the value of `name`. See [`Options::recursive`].
+    fn expand_value(
+        &mut self,
+        name: &str,
+        val: &OsStr,
+        span: Span,
+    ) -> Result<OsString, EnvExpansionError> {
+        if let Some(i) = self.stack.iter().position(|n| n == name) {
+            let mut chain = self.stack[i..].to_vec();
+            chain.push(name.to_string());
+            return Err(EnvExpansionError::Cycle {
+                chain,
+                location: self.location(span),
+            });
+        }
+        // Values that are not valid Unicode cannot contain placeholders
+        let Some(text) = val.to_str() else {
+            return Ok(val.to_os_string());
+        };
+        let nodes = ast::parse_value(text, self.options);
+
+        self.stack.push(name.to_string());
+        let outer = self.outer.replace(self.outer.unwrap_or(span));
+        let mut out = OsString::with_capacity(val.len());
+        let result = self.render(&nodes, &mut out);
+        self.outer = outer;
+        self.stack.pop();
+        result.map(|()| out)
+    }
+
+    fn assign(&mut self, name: &str, val: &OsStr) {
+        match self.assignments.iter_mut().find(|(n, _)| n == name) {
+            Some((_, old)) => val.clone_into(old),
+            None => self
+                .assignments
+                .push((name.to_string(), val.to_os_string())),
+        }
+    }
+
+    fn lookup(&mut self, var: &Var<'_>) -> Result<Cow<'a, OsStr>, EnvExpansionError> {
+        match self.var(&var.name, var.span)? {
+            Some(val) => Ok(val),
+            None => self.missing(var).map(Cow::Owned),
+        }
+    }
+
+    /// Resolves `~<user>`, or returns `None` to keep it as is.
+    fn tilde(
+        &mut self,
+        user: &str,
+        span: Span,
+    ) -> Result<Option<Cow<'a, OsStr>>, EnvExpansionError> {
+        if let Some(name) = path::tilde_var(user) {
+            return self.var(name, span);
+        }
+        match path::user_home(user) {
+            Some(home) if !self.os && home.to_str().is_none() => {
+                Err(EnvExpansionError::NotUnicode {
+                    name: format!("~{user}"),
+                    value: home,
+                    location: self.location(span),
+                })
+            }
+            home => Ok(home.map(Cow::Owned)),
+        }
+    }
+
+    fn missing(&mut self, var: &Var<'_>) -> Result<OsString, EnvExpansionError> {
+        match self.options.on_missing {
+            OnMissing::Empty => Ok(OsString::new()),
+            OnMissing::Error => Err(EnvExpansionError::MissingVar {
+                name: var.name.to_string(),
+                location: self.location(var.span),
+            }),
+            OnMissing::Collect => {
+                self.missing.push(Missing {
+                    name: var.name.to_string(),
+                    location: self.location(var.span),
+                });
+                Ok(OsString::new())
+            }
+            OnMissing::Keep => Ok(var.to_string().into()),
+        }
+    }
+
+    fn render(&mut self, nodes: &[Node<'_>], out: &mut OsString) -> Result<(), EnvExpansionError> {
+        nodes
+            .iter()
+            .try_for_each(|node| self.render_node(node, out))
+    }
+
+    pub(crate) fn render_node(
+        &mut self,
+        node: &Node<'_>,
+        out: &mut OsString,
+    ) -> Result<(), EnvExpansionError> {
+        match node {
+            Node::Text { text, .. } => out.push(text.as_ref()),
+            Node::Escape { raw, .. } => out.push(&raw[raw.len() - 1..]),
+            Node::Tilde { user, span } => match self.tilde(user, *span)? {
+                Some(home) => out.push(&home),
+                None => {
+                    out.push("~");
+                    out.push(user.as_ref());
+                }
+            },
+            Node::Var(var) => match &var.op {
+                Some(op) => {
+                    let val = self.apply(op, var)?;
+                    out.push(&val);
+                }
+                None => {
+                    let val = self.lookup(var)?;
+                    out.push(&val);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn render_word(&mut self, word: &[Node<'_>]) -> Result<OsString, EnvExpansionError> {
+        let mut out = OsString::new();
+        self.render(word, &mut out)?;
+        Ok(out)
+    }
+
+    /// Evaluates `${name<op>word}` or `%name:~start,length%`.
+    fn apply(&mut self, op: &Op<'_>, var: &Var<'_>) -> Result<Cow<'a, OsStr>, EnvExpansionError> {
+        let (name, span) = (var.name.as_ref(), var.span);
+        let val = self.var(name, span)?;
+        match op {
+            Op::Default { colon, word } => match val {
+                Some(val) if !(*colon && val.is_empty()) => Ok(val),
+                _ => self.render_word(word).map(Cow::Owned),
+            },
+            Op::Error { colon, word } => match val {
+                Some(val) if !(*colon && val.is_empty()) => Ok(val),
+                _ => Err(EnvExpansionError::Required {
+                    name: name.to_string(),
+                    message: self.render_word(word)?.to_string_lossy().into_owned(),
+                    location: self.location(span),
+                }),
+            },
+            Op::Alternate { colon, word } => match val {
+                Some(val) if !(*colon && val.is_empty()) => self.render_word(word).map(Cow::Owned),
+                _ => Ok(Cow::Borrowed(OsStr::new(""))),
+            },
+            Op::Assign { colon, word } => match val {
+                Some(val) if !(*colon && val.is_empty()) => Ok(val),
+                _ => {
+                    let val = self.render_word(word)?;
+                    self.assign(name, &val);
+                    Ok(Cow::Owned(val))
+                }
+            },
+            Op::Substring { start, length, .. } => match val {
+                Some(val) => {
+                    let val = substring(&val.to_string_lossy(), *start, *length).into();
+                    Ok(Cow::Owned(val))
+                }
+                None => self.missing(var).map(Cow::Owned),
+            },
+        }
+    }
+
+    /// Reports the collected missing variables, or returns the assignments.
+    pub(crate) fn finish(self) -> Result<Vec<(String, OsString)>, EnvExpansionError> {
+        if self.missing.is_empty() {
+            Ok(self.assignments)
+        } else {
+            Err(EnvExpansionError::MissingVars(self.missing))
+        }
+    }
+}
+
+/// Takes the characters of `val` selected by cmd.exe's `:~start,length`.
+fn substring(val: &str, start: i64, length: Option<i64>) -> &str {
+    let count = val.chars().count() as i64;
+    let start = if start < 0 {
+        (count + start).max(0)
+    } else {
+        start.min(count)
+    };
+    let end = match length {
+        None => count,
+        Some(length) if length < 0 => count + length,
+        Some(length) => start.saturating_add(length).min(count),
+    };
+    if end <= start {
+        return "";
+    }
+    let offset = |n: i64| {
+        val.char_indices()
+            .nth(n as usize)
+            .map_or(val.len(), |(i, _)| i)
+    };
+    &val[offset(start)..offset(end)]
+}
+
+fn into_string(s: OsString) -> String {
+    s.into_string()
+        .unwrap_or_else(|s| s.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Syntax;
+    use std::collections::HashMap;
+    use std::sync::LazyLock;
+
+    static CONFIG: LazyLock<Template> = LazyLock::new(|| {
+        Options::new()
+            .syntax(Syntax::Unix)
+            .parse("${SCHEME:-http}://$HOST:${PORT:-80}/")
+            .unwrap()
+    });
+
+    #[test]
+    fn test_render_many_times() {
+        let a = HashMap::from([("HOST", "a.example")]);
+        let b = HashMap::from([("SCHEME", "https"), ("HOST", "b.example"), ("PORT", "443")]);
+        assert_eq!(CONFIG.render(&a).unwrap(), "http://a.example:80/");
+        assert_eq!(CONFIG.render(&b).unwrap(), "https://b.example:443/");
+    }
+
+    #[test]
+    fn test_render_uses_template_options() {
+        let template = Options::new()
+            .syntax(Syntax::Windows)
+            .strict()
+            .parse("%A%")
+            .unwrap();
+        let empty: HashMap<&str, &str> = HashMap::new();
+        assert!(template.render(&empty).is_err());
+        assert_eq!(template.render(&HashMap::from([("A", "1")])).unwrap(), "1");
+    }
+
+    #[test]
+    fn test_references() {
+        let template = Options::new()
+            .syntax(Syntax::Unix)
+            .parse("$A ${B:?required} ${A:-$C}")
+            .unwrap();
+        let refs = template.references();
+        let names: Vec<_> = refs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["A", "B", "A", "C"]);
+        assert_eq!(refs[0].span, Span { start: 0, end: 2 });
+        assert!(refs[1].is_required());
+        assert!(refs[2].has_default());
+        assert_eq!(refs[3].op, None);
+        assert_eq!(template.names(), ["A", "B", "C"]);
+
+        let windows = Options::new()
+            .syntax(Syntax::Windows)
+            .parse("%A% %B% %A%")
+            .unwrap();
+        assert_eq!(windows.names(), ["A", "B"]);
+        assert_eq!(windows.references()[1].span, Span { start: 4, end: 7 });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_render_not_unicode() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let template = Template::parse("${TEMPLATE_NOT_UNICODE:-x}/bin");
+        let bytes = b"/opt/\xff";
+        let _env = crate::env_lock();
+        // SAFETY: no other test reads or writes this variable.
+        unsafe { std::env::set_var("TEMPLATE_NOT_UNICODE", std::ffi::OsStr::from_bytes(bytes)) };
+        let err = template.render_env().unwrap_err();
+        let EnvExpansionError::NotUnicode {
+            name,
+            value,
+            location,
+        } = err
+        else {
+            panic!("expected NotUnicode, got {err:?}");
+        };
+        assert_eq!(name, "TEMPLATE_NOT_UNICODE");
+        assert_eq!(value.as_bytes(), bytes);
+        assert_eq!(location.span, Span { start: 0, end: 26 });
+        assert_eq!(
+            template.render_os(&Env).unwrap().as_bytes(),
+            b"/opt/\xff/bin"
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_render_tilde() {
+        let template = Options::new()
+            .syntax(Syntax::Unix)
+            .tilde(true)
+            .parse("cd ~/src && cd ~- && ${DIRS:-~+:~}")
+            .unwrap();
+        let vars = HashMap::from([("HOME", "/home/alice"), ("PWD", "/tmp"), ("OLDPWD", "/srv")]);
+        assert_eq!(
+            template.render(&vars).unwrap(),
+            "cd /home/alice/src && cd /srv && /tmp:/home/alice"
+        );
+        let empty: HashMap<&str, &str> = HashMap::new();
+        assert_eq!(
+            template.render(&empty).unwrap(),
+            "cd ~/src && cd ~- && ~+:~"
+        );
+    }
+
+    #[test]
+    fn test_render_recursive() {
+        let vars = HashMap::from([
+            ("DATA_DIR", "/var/lib/${APP}"),
+            ("APP", "demo"),
+            ("LOG_DIR", "$DATA_DIR/logs"),
+            ("A", "${B:-x}"),
+            ("B", "$C"),
+            ("C", "$A"),
+            ("SELF", "$SELF"),
+            ("BROKEN", "$DATA_DIR/$UNSET_DIR"),
+        ]);
+        let options = Options::new().syntax(Syntax::Unix);
+        let render = |options: &Options, input: &str| options.parse(input)?.render(&vars);
+
+        assert_eq!(render(&options, "$LOG_DIR").unwrap(), "$DATA_DIR/logs");
+        let recursive = options.clone().recursive(8);
+        assert_eq!(
+            render(&recursive, "$LOG_DIR").unwrap(),
+            "/var/lib/demo/logs"
+        );
+        let shallow = options.clone().recursive(1);
+        assert_eq!(
+            render(&shallow, "$LOG_DIR").unwrap(),
+            "/var/lib/${APP}/logs"
+        );
+
+        let err = render(&recursive, "x: $A").unwrap_err();
+        let EnvExpansionError::Cycle { chain, location } = &err else {
+            panic!("expected Cycle, got {err:?}");
+        };
+        assert_eq!(chain, &["A", "B", "C", "A"]);
+        assert_eq!(location.span, Span { start: 3, end: 5 });
+        assert_eq!(
+            err.to_string(),
+            "Cycle in variable references: A -> B -> C -> A"
+        );
+        assert!(matches!(
+            render(&recursive, "$SELF"),
+            Err(EnvExpansionError::Cycle { chain, .. }) if chain == ["SELF", "SELF"]
+        ));
+
+        // Errors inside values point at the placeholder in the template
+        let err = render(&recursive.strict(), "a\n $LOG_DIR $BROKEN").unwrap_err();
+        let EnvExpansionError::MissingVar { name, location } = err else {
+            panic!("expected MissingVar, got {err:?}");
+        };
+        assert_eq!(name, "UNSET_DIR");
+        assert_eq!((location.line, location.column), (2, 11));
+    }
+
+    #[test]
+    fn test_render_kubernetes() {
+        let vars = HashMap::from([("HOST", "db"), ("EMPTY", "")]);
+        let render = |input: &str| Options::kubernetes().parse(input)?.render(&vars);
+        // Cases from the Kubernetes expansion tests
+        for (input, expected) in [
+            ("$(HOST)", "db"),
+            ("$(HOST)-$(HOST)", "db-db"),
+            ("$(EMPTY)", ""),
+            ("$(UNSET)", "$(UNSET)"),
+            ("$$(HOST)", "$(HOST)"),
+            ("$$$(HOST)", "$db"),
+            ("$$$$(HOST)", "$$(HOST)"),
+            ("$$", "$"),
+            ("$HOST", "$HOST"),
+            ("${HOST}", "${HOST}"),
+            ("$(HOST", "$(HOST"),
+            ("$()", "$()"),
+            ("foo$", "foo$"),
+            ("$(HOST)$(", "db$("),
+        ] {
+            assert_eq!(render(input).unwrap(), expected, "{input}");
+        }
+
+        let windows = Options::new()
+            .syntax(Syntax::Windows)
+            .on_missing(OnMissing::Keep);
+        assert_eq!(
+            windows
+                .parse("%HOST% %UNSET%")
+                .unwrap()
+                .render(&vars)
+                .unwrap(),
+            "db %UNSET%"
+        );
+    }
+
+    #[test]
+    fn test_render_substring() {
+        let vars = HashMap::from([("PATH", "C:\\Windows\\system32"), ("WORD", "héllo")]);
+        let windows = Options::new().syntax(Syntax::Windows);
+        let render = |input: &str| windows.parse(input).unwrap().render(&vars).unwrap();
+        for (input, expected) in [
+            ("%PATH:~0,10%", "C:\\Windows"),
+            ("%PATH:~3%", "Windows\\system32"),
+            ("%PATH:~-8%", "system32"),
+            ("%PATH:~-8,6%", "system"),
+            ("%PATH:~3,-9%", "Windows"),
+            ("%PATH:~-100,2%", "C:"),
+            ("%PATH:~100%", ""),
+            ("%PATH:~5,-100%", ""),
+            ("%PATH:~+03,07%", "Windows"),
+            ("%PATH:~-0%", "C:\\Windows\\system32"),
+            ("%PATH:~-99999999999999999999,2%", "C:"),
+            ("%WORD:~1,3%", "éll"),
+            ("%WORD:~-2%", "lo"),
+            ("%UNSET:~0,3%", ""),
+        ] {
+            assert_eq!(render(input), expected, "{input}");
+        }
+
+        let keep = windows.clone().on_missing(OnMissing::Keep);
+        let template = keep.parse("%UNSET:~0,3%").unwrap();
+        assert_eq!(template.render(&vars).unwrap(), "%UNSET:~0,3%");
+        let strict = windows.strict().parse("%UNSET:~0,3%").unwrap();
+        assert!(matches!(
+            strict.render(&vars),
+            Err(EnvExpansionError::MissingVar { name, .. }) if name == "UNSET"
+        ));
+    }
+
+    #[test]
+    fn test_template_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Template>();
+    }
+}